@@ -0,0 +1,543 @@
+use serde::Deserialize;
+use std::ops::ControlFlow;
+use std::thread;
+use std::time::Duration;
+use log::{info, trace, warn};
+
+use crate::attachments::{self, SaveAttachments};
+use crate::command::RunCommand;
+use crate::config::{Account, Config};
+use crate::error::{ActionError, ListenerError};
+use crate::folder::{self, MoveTarget};
+use crate::mime;
+use crate::notification::{Notifier, Notify};
+use crate::paths;
+use crate::rules::{Message, Rule};
+use crate::smtp::{AutoReply, Forward, Mailer};
+use crate::sound::{self, Sound};
+use crate::template::{Context, Template};
+use crate::watchdog::Watchdog;
+use crate::webhook::{Webhook, WebhookQueue};
+use crate::worker::{self, Session};
+
+// Waited before the first retry of a failed action, and once more before every next one
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Whether the mail is still in the watched mailbox after an action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Kept,
+    Removed,
+}
+
+/// Everything an action may need besides its own settings.
+pub struct ActionContext<'a> {
+    pub session: &'a mut Session,
+    pub config: &'a Config,
+    pub account: &'a Account,
+    pub mailbox: &'a str,
+    pub rule: &'a Rule,
+    pub message: &'a Message,
+    pub watchdog: &'a Watchdog,
+    pub webhooks: &'a WebhookQueue,
+    pub notifier: &'a Notifier,
+    // None without an [smtp] section
+    pub mailer: Option<&'a Mailer>,
+}
+
+impl ActionContext<'_> {
+    /// Values for the placeholders of templates in action settings.
+    pub fn template_context(&self) -> Context<'_> {
+        Context { account: &self.account.name, mailbox: self.mailbox, rule: &self.rule.name, message: self.message }
+    }
+}
+
+/// Something a rule does with a matching mail.
+pub trait Action {
+    /// Name rules refer to the action with.
+    fn name(&self) -> &'static str;
+
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError>;
+}
+
+/// Built-in actions, by the name rules refer to them with.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    // Move mail to given mailbox; no further rules are run for it afterwards
+    Move(MoveMail),
+    // Copy mail to given mailbox, leaving it where it is
+    Copy(CopyMail),
+    // Set a system flag like "\\Flagged" or a keyword like "$Work"
+    Flag(SetFlag),
+    // Mark mail as seen
+    MarkRead,
+    // Delete mail for good; no further rules are run for it afterwards
+    Delete,
+    // Play audio_file, or the given file
+    PlaySound(PlaySound),
+    // Run a program with arguments, environment and input filled in from the mail
+    RunCommand(RunCommand),
+    // Send an HTTP request, by default POSTing a summary of the mail as JSON; retried
+    // later when the server is unreachable or failing, so only a refusal fails the action
+    Webhook(Webhook),
+    // Show a desktop notification with the sender and subject, and buttons to open the
+    // mail or mark it read
+    Notify(Notify),
+    // Write a line to the log, e.g. "Menu from {from}: {subject}"
+    Log(LogLine),
+    // Save the matching attachments to a directory, never overwriting existing files
+    SaveAttachments(SaveAttachments),
+    // Send the mail on through the SMTP server, attached or quoted
+    Forward(Forward),
+    // Answer the sender through the SMTP server, unless the mail is automatic or they
+    // were answered recently
+    AutoReply(AutoReply),
+}
+
+/// What to do when an action fails.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPolicy {
+    // Log the failure and go on with the next action
+    #[default]
+    Continue,
+    // Skip the remaining actions of the rule
+    Abort,
+    // Try again up to this many times, then go on as with continue
+    Retry(u32),
+}
+
+/// An action as listed in a rule: its name, a table with its settings, or that table
+/// with an error policy added, e.g. `{"run_command": {...}, "on_error": {"retry": 3}}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "serde_json::Value")]
+pub struct RuleAction {
+    pub kind: ActionKind,
+    pub on_error: ErrorPolicy,
+}
+
+// Taken apart by hand rather than with an untagged enum, so that a misspelled action
+// gets a better error than "data did not match any variant"
+impl TryFrom<serde_json::Value> for RuleAction {
+    type Error = String;
+
+    fn try_from(mut value: serde_json::Value) -> Result<RuleAction, String> {
+        let on_error = match value.as_object_mut().and_then(|table| table.remove("on_error")) {
+            Some(policy) => serde_json::from_value(policy).map_err(|e| format!("invalid on_error: {e}"))?,
+            None => ErrorPolicy::default(),
+        };
+        // Actions without settings given as a table to have a policy, e.g. {"delete": {}}
+        if let Some(table) = value.as_object() {
+            if let [(name, settings)] = table.iter().collect::<Vec<_>>()[..] {
+                if settings.is_null() || settings.as_object().is_some_and(|settings| settings.is_empty()) {
+                    value = serde_json::Value::String(name.clone());
+                }
+            }
+        }
+
+        // Actions whose settings all have defaults may be given by name, e.g. "play_sound";
+        // for the others, the settings missing in that form are what's wrong
+        let kind = serde_json::from_value(value.clone()).or_else(|e| match value {
+            serde_json::Value::String(name) => serde_json::from_value(serde_json::json!({ name: {} })),
+            _ => Err(e),
+        });
+        Ok(RuleAction { kind: kind.map_err(|e| e.to_string())?, on_error })
+    }
+}
+
+impl From<ActionKind> for RuleAction {
+    fn from(kind: ActionKind) -> RuleAction {
+        RuleAction { kind, on_error: ErrorPolicy::default() }
+    }
+}
+
+impl ActionKind {
+    pub fn action(&self) -> &dyn Action {
+        match self {
+            ActionKind::Move(action) => action,
+            ActionKind::Copy(action) => action,
+            ActionKind::Flag(action) => action,
+            ActionKind::MarkRead => &MarkRead,
+            ActionKind::Delete => &Delete,
+            ActionKind::PlaySound(action) => action,
+            ActionKind::RunCommand(action) => action,
+            ActionKind::Webhook(action) => action,
+            ActionKind::Notify(action) => action,
+            ActionKind::Log(action) => action,
+            ActionKind::SaveAttachments(action) => action,
+            ActionKind::Forward(action) => action,
+            ActionKind::AutoReply(action) => action,
+        }
+    }
+
+    pub fn validate(&self, rule_name: &str, problems: &mut Vec<String>) {
+        match self {
+            ActionKind::Move(MoveMail(target)) | ActionKind::Copy(CopyMail(target))
+                if target.folder.split('/').any(|level| level.is_empty()) =>
+            {
+                problems.push(format!("rule \"{rule_name}\" puts mail into a folder with an empty name"));
+            },
+            ActionKind::Flag(SetFlag(flag)) if !valid_flag(flag) => {
+                problems.push(format!("rule \"{rule_name}\" sets invalid flag {flag}"));
+            },
+            ActionKind::PlaySound(sound) => sound.validate(rule_name, problems),
+            ActionKind::RunCommand(command) => command.validate(rule_name, problems),
+            ActionKind::Webhook(webhook) => webhook.validate(rule_name, problems),
+            ActionKind::SaveAttachments(save) => save.filter.validate(rule_name, problems),
+            ActionKind::Forward(forward) => forward.validate(rule_name, problems),
+            ActionKind::AutoReply(reply) => reply.validate(rule_name, problems),
+            _ => {},
+        }
+    }
+}
+
+impl RuleAction {
+    pub fn validate(&self, rule_name: &str, problems: &mut Vec<String>) {
+        self.kind.validate(rule_name, problems);
+        match (self.on_error, &self.kind) {
+            (ErrorPolicy::Retry(0), _) => problems.push(format!("rule \"{rule_name}\" retries an action 0 times")),
+            // Failures that may pass are queued and retried there, only refusals get here
+            (ErrorPolicy::Retry(_), ActionKind::Webhook(_)) => problems.push(format!(
+                "rule \"{rule_name}\" retries a webhook, but the webhook queue already retries failures that may pass"
+            )),
+            _ => {},
+        }
+    }
+}
+
+// "\Flagged" style system flag or keyword, both atoms in IMAP
+fn valid_flag(flag: &str) -> bool {
+    let keyword = flag.strip_prefix('\\').unwrap_or(flag);
+    !keyword.is_empty() && keyword.bytes().all(|c| c.is_ascii_graphic() && !b"(){%*\"\\]".contains(&c))
+}
+
+/// Runs the actions of the rule in order, following their error policies. Only errors
+/// that need a reconnect are returned.
+pub fn run_actions(context: &mut ActionContext<'_>) -> Result<Outcome, ListenerError> {
+    let (rule, uid, watchdog) = (context.rule, context.message.uid, context.watchdog);
+    for rule_action in &rule.actions {
+        let next = run_action(rule_action, &rule.name, uid, watchdog, RETRY_DELAY, |action| action.run(context))?;
+        if let ControlFlow::Break(outcome) = next {
+            return Ok(outcome);
+        }
+    }
+    Ok(Outcome::Kept)
+}
+
+// Runs one action as often as its error policy allows, breaks when the rest of the
+// rule's actions are to be skipped
+fn run_action(
+    rule_action: &RuleAction,
+    rule_name: &str,
+    uid: u32,
+    watchdog: &Watchdog,
+    retry_delay: Duration,
+    mut run: impl FnMut(&dyn Action) -> Result<Outcome, ActionError>,
+) -> Result<ControlFlow<Outcome>, ListenerError> {
+    let action = rule_action.kind.action();
+    let mut attempt = 0;
+    loop {
+        let error = match run(action) {
+            Ok(Outcome::Kept) => return Ok(ControlFlow::Continue(())),
+            Ok(Outcome::Removed) => return Ok(ControlFlow::Break(Outcome::Removed)),
+            Err(ActionError::Listener(error)) => return Err(error),
+            Err(ActionError::Failed(error)) => error,
+        };
+        warn!("Action {} of rule \"{rule_name}\" failed on mail of UID {uid}: {error}", action.name());
+
+        match rule_action.on_error {
+            ErrorPolicy::Retry(retries) if attempt < retries => {
+                attempt += 1;
+                thread::sleep(retry_delay * attempt);
+                watchdog.feed();
+            },
+            ErrorPolicy::Abort => return Ok(ControlFlow::Break(Outcome::Kept)),
+            _ => return Ok(ControlFlow::Continue(())),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MoveMail(pub MoveTarget);
+
+impl Action for MoveMail {
+    fn name(&self) -> &'static str {
+        "move"
+    }
+
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        let uid = context.message.uid;
+        worker::move_to_folder(context.session, uid, &self.0).map_err(|e| ActionError::imap(uid, e))?;
+        Ok(Outcome::Removed)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CopyMail(pub MoveTarget);
+
+impl Action for CopyMail {
+    fn name(&self) -> &'static str {
+        "copy"
+    }
+
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        let uid = context.message.uid;
+        let session = &mut *context.session;
+        session.folders.prepare(&mut session.imap, &self.0)
+            .and_then(|folder| folder::uid_copy(&mut session.imap, &uid.to_string(), &folder))
+            .map_err(|e| ActionError::imap(uid, e))?;
+        Ok(Outcome::Kept)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SetFlag(pub String);
+
+impl Action for SetFlag {
+    fn name(&self) -> &'static str {
+        "flag"
+    }
+
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        let uid = context.message.uid;
+        context.session.imap.uid_store(uid.to_string(), format!("+FLAGS.SILENT ({})", self.0))
+            .map_err(|e| ActionError::imap(uid, e))?;
+        Ok(Outcome::Kept)
+    }
+}
+
+pub struct MarkRead;
+
+impl Action for MarkRead {
+    fn name(&self) -> &'static str {
+        "mark_read"
+    }
+
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        SetFlag("\\Seen".to_string()).run(context)
+    }
+}
+
+pub struct Delete;
+
+impl Action for Delete {
+    fn name(&self) -> &'static str {
+        "delete"
+    }
+
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        let uid = context.message.uid;
+        worker::delete(context.session, uid).map_err(|e| ActionError::imap(uid, e))?;
+        Ok(Outcome::Removed)
+    }
+}
+
+/// Settings of the play_sound action, each falling back to the [sound] defaults.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PlaySound {
+    // Path with "~" and "$VARIABLES" expanded, audio_file when not given
+    pub file: Option<String>,
+    pub volume: Option<f32>,
+    pub repeat: Option<u32>,
+}
+
+impl PlaySound {
+    fn validate(&self, rule_name: &str, problems: &mut Vec<String>) {
+        if let Some(Err(e)) = self.file.as_deref().map(paths::expand) {
+            problems.push(format!("rule \"{rule_name}\" plays a sound: {e}"));
+        }
+        if let Some(problem) = self.volume.and_then(sound::check_volume) {
+            problems.push(format!("rule \"{rule_name}\" plays a sound with {problem}"));
+        }
+        if self.repeat == Some(0) {
+            problems.push(format!("rule \"{rule_name}\" plays a sound 0 times"));
+        }
+    }
+}
+
+impl Action for PlaySound {
+    fn name(&self) -> &'static str {
+        "play_sound"
+    }
+
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        let message = context.message;
+        trace!("New mail from {}: \"{}\"", message.senders_text(), message.subject);
+
+        let defaults = &context.config.sound;
+        let file = self.file.as_deref().unwrap_or(&context.config.audio_file);
+        let sound = Sound {
+            file: paths::expand(file).map_err(|e| ActionError::Failed(e.into()))?,
+            volume: self.volume.unwrap_or(defaults.volume),
+            repeat: self.repeat.unwrap_or(defaults.repeat),
+        };
+        sound::play(sound, defaults).map_err(ActionError::Failed)?;
+        Ok(Outcome::Kept)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LogLine(pub Template);
+
+impl Action for LogLine {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        info!("{}", self.0.render(&context.template_context(), |value| value));
+        Ok(Outcome::Kept)
+    }
+}
+
+impl Action for SaveAttachments {
+    fn name(&self) -> &'static str {
+        "save_attachments"
+    }
+
+    // A failure to fetch or write one attachment is logged and the others are still saved,
+    // then the action fails; retrying it finds the ones that worked already saved
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        let message = context.message;
+        let selected: Vec<_> = message.attachments.iter().filter(|a| self.filter.matches(a)).collect();
+        if selected.is_empty() {
+            return Ok(Outcome::Kept);
+        }
+        let directory = self.directory(&context.template_context());
+
+        let mut failed = 0;
+        for attachment in &selected {
+            let query = format!("BODY.PEEK[{}]", attachment.section());
+            let messages = match context.session.imap.uid_fetch(message.uid.to_string(), query) {
+                Ok(messages) => messages,
+                Err(e) => match ActionError::imap(message.uid, e) {
+                    ActionError::Failed(error) => {
+                        warn!("Failed to fetch attachment {} of mail of UID {}: {error}", attachment.section(), message.uid);
+                        failed += 1;
+                        continue;
+                    },
+                    error => return Err(error),
+                },
+            };
+            context.watchdog.feed();
+            let section = imap_proto::types::SectionPath::Part(attachment.path.clone(), None);
+            let Some(data) = messages.iter().next().and_then(|fetch| fetch.section(&section)) else {
+                warn!("Server returned no data for attachment {} of mail of UID {}", attachment.section(), message.uid);
+                failed += 1;
+                continue;
+            };
+
+            match attachments::save(&directory, attachment, &mime::decode_transfer(attachment.encoding, data)) {
+                Ok(path) => info!("Saved attachment of mail of UID {} to {}", message.uid, path.display()),
+                Err(e) => {
+                    warn!("Failed to save attachment to {}: {e}", directory.display());
+                    failed += 1;
+                },
+            }
+        }
+
+        if failed > 0 {
+            return Err(ActionError::Failed(format!("{failed} of {} attachments couldn't be saved", selected.len()).into()));
+        }
+        Ok(Outcome::Kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(json: &str) -> Vec<String> {
+        let sound: PlaySound = serde_json::from_str(json).unwrap();
+        let mut problems = Vec::new();
+        sound.validate("menu", &mut problems);
+        problems
+    }
+
+    #[test]
+    fn validates_play_sound_settings() {
+        assert!(problems("{}").is_empty());
+        assert!(problems(r#"{"file": "~/beep.wav", "volume": 0.5, "repeat": 3}"#).is_empty());
+        assert_eq!(problems(r#"{"volume": -2}"#), ["rule \"menu\" plays a sound with volume -2 has to be 0 or more"]);
+        assert_eq!(problems(r#"{"repeat": 0}"#), ["rule \"menu\" plays a sound 0 times"]);
+        assert_eq!(problems(r#"{"file": "$IMAP_LISTENER_TEST_UNSET_SOUND/a.wav"}"#).len(), 1);
+    }
+
+    fn action(json: &str) -> Result<RuleAction, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn parses_actions_in_all_forms() {
+        assert!(matches!(action(r#""mark_read""#).unwrap().kind, ActionKind::MarkRead));
+        assert!(matches!(action(r#""play_sound""#).unwrap().kind, ActionKind::PlaySound(PlaySound { file: None, .. })));
+        let delete = action(r#"{"delete": {}, "on_error": "abort"}"#).unwrap();
+        assert!(matches!((delete.kind, delete.on_error), (ActionKind::Delete, ErrorPolicy::Abort)));
+        let flag = action(r#"{"flag": "\\Flagged", "on_error": {"retry": 3}}"#).unwrap();
+        assert!(matches!((flag.kind, flag.on_error), (ActionKind::Flag(SetFlag(flag)), ErrorPolicy::Retry(3)) if flag == "\\Flagged"));
+        let copy = action(r#"{"copy": {"folder": "Archive/Menus", "create": true}}"#).unwrap();
+        assert!(matches!((copy.kind, copy.on_error), (ActionKind::Copy(CopyMail(target)), ErrorPolicy::Continue) if target.create));
+    }
+
+    #[test]
+    fn names_what_is_wrong_with_an_action() {
+        assert!(action(r#""shout""#).unwrap_err().starts_with("unknown variant `shout`, expected one of `move`"));
+        assert!(action(r#"{"shout": {"volume": 1}}"#).unwrap_err().starts_with("unknown variant `shout`"));
+        assert_eq!(action(r#"{"log": "Menu", "flag": "$Menu"}"#).unwrap_err(), "invalid value: map, expected map with a single key");
+
+        // Misspelled settings
+        assert_eq!(action(r#"{"play_sound": {"volum": 1}}"#).unwrap_err(), "unknown field `volum`, expected one of `file`, `volume`, `repeat`");
+        assert_eq!(
+            action(r#"{"move": {"folder": "Menus", "craete": true}}"#).unwrap_err(),
+            "invalid folder: unknown field `craete`, expected one of `folder`, `create`, `subscribe`",
+        );
+        assert!(action(r#"{"delete": {}, "on_error": "ignore"}"#).unwrap_err().starts_with("invalid on_error: unknown variant `ignore`"));
+
+        // Missing settings
+        assert_eq!(action(r#""move""#).unwrap_err(), "invalid folder: missing field `folder`");
+        assert_eq!(action(r#"{"move": {}}"#).unwrap_err(), "invalid folder: missing field `folder`");
+        assert_eq!(action(r#"{"run_command": {"args": ["x"]}}"#).unwrap_err(), "missing field `program`");
+        assert_eq!(action(r#"{"delete": {}, "on_error": "retry"}"#).unwrap_err(), "invalid on_error: invalid type: unit variant, expected newtype variant");
+    }
+
+    // Runs the action with the given policy, failing the given number of times first
+    fn attempts(on_error: &str, failures: usize, then: Result<Outcome, ActionError>) -> (ControlFlow<Outcome>, usize) {
+        let rule_action = action(&format!(r#"{{"mark_read": {{}}, "on_error": {on_error}}}"#)).unwrap();
+        let watchdog = Watchdog::spawn(Duration::from_secs(3600));
+        let mut results = (0..failures).map(|_| Err(ActionError::Failed("server said no".into()))).collect::<Vec<_>>();
+        results.push(then);
+        results.reverse();
+        let mut runs = 0;
+        let next = run_action(&rule_action, "menu", 42, &watchdog, Duration::ZERO, |_| {
+            runs += 1;
+            results.pop().unwrap()
+        });
+        (next.unwrap(), runs)
+    }
+
+    #[test]
+    fn follows_error_policies() {
+        assert_eq!(attempts(r#""continue""#, 0, Ok(Outcome::Kept)), (ControlFlow::Continue(()), 1));
+        assert_eq!(attempts(r#""continue""#, 1, Ok(Outcome::Kept)), (ControlFlow::Continue(()), 1));
+        assert_eq!(attempts(r#""abort""#, 1, Ok(Outcome::Kept)), (ControlFlow::Break(Outcome::Kept), 1));
+        assert_eq!(attempts(r#"{"retry": 2}"#, 2, Ok(Outcome::Kept)), (ControlFlow::Continue(()), 3));
+        assert_eq!(attempts(r#"{"retry": 2}"#, 3, Ok(Outcome::Kept)), (ControlFlow::Continue(()), 3));
+        // Removing the mail ends the rule, even on a retry
+        assert_eq!(attempts(r#"{"retry": 2}"#, 1, Ok(Outcome::Removed)), (ControlFlow::Break(Outcome::Removed), 2));
+    }
+
+    #[test]
+    fn connection_errors_are_never_retried() {
+        let rule_action = action(r#"{"mark_read": {}, "on_error": {"retry": 3}}"#).unwrap();
+        let watchdog = Watchdog::spawn(Duration::from_secs(3600));
+        let mut runs = 0;
+        let next = run_action(&rule_action, "menu", 42, &watchdog, Duration::ZERO, |_| {
+            runs += 1;
+            Err(ActionError::Listener(ListenerError::Connection(imap::Error::ConnectionLost)))
+        });
+        assert!(matches!(next, Err(ListenerError::Connection(imap::Error::ConnectionLost))));
+        assert_eq!(runs, 1);
+    }
+}
@@ -0,0 +1,153 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::fmt;
+
+/// One mailbox from an address header, decoded.
+#[derive(Debug, Clone, Default)]
+pub struct Address {
+    // Display name, e.g. "John Doe"
+    pub name: Option<String>,
+    // Local part, e.g. "john.doe"
+    pub mailbox: Option<String>,
+    // Domain, e.g. "example.com"
+    pub host: Option<String>,
+}
+
+impl Address {
+    /// Full address, e.g. "john.doe@example.com".
+    pub fn address(&self) -> Option<String> {
+        match (&self.mailbox, &self.host) {
+            (Some(mailbox), Some(host)) => Some(format!("{mailbox}@{host}")),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.name, self.address()) {
+            (Some(name), Some(address)) => write!(f, "{name} <{address}>"),
+            (Some(name), None) => f.write_str(name),
+            (None, Some(address)) => f.write_str(&address),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Entry of a sender allow-list.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub enum AddressPattern {
+    // "John Doe": display name, exactly
+    Name(String),
+    // "john.doe@example.com": full address, case-insensitive
+    Address(String),
+    // "*@example.com": any address in the domain, "*@*.example.com" also in its subdomains
+    Domain(String),
+    // "/^orders?@/": regex tried on the full address only, as anyone can pick any display name
+    Regex(Regex),
+}
+
+impl TryFrom<String> for AddressPattern {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<AddressPattern, String> {
+        if let Some(regex) = pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+            return Regex::new(regex)
+                .map(AddressPattern::Regex)
+                .map_err(|e| format!("invalid sender regex {pattern}: {e}"));
+        }
+        if let Some(domain) = pattern.strip_prefix("*@") {
+            if domain.is_empty() || domain.contains('@') {
+                return Err(format!("invalid sender domain pattern {pattern}"));
+            }
+            return Ok(AddressPattern::Domain(domain.to_lowercase()));
+        }
+        if pattern.contains('@') {
+            return Ok(AddressPattern::Address(pattern.to_lowercase()));
+        }
+        Ok(AddressPattern::Name(pattern))
+    }
+}
+
+impl AddressPattern {
+    pub fn matches(&self, address: &Address) -> bool {
+        match self {
+            AddressPattern::Name(name) => address.name.as_ref() == Some(name),
+            AddressPattern::Address(expected) => {
+                address.address().is_some_and(|actual| actual.to_lowercase() == *expected)
+            },
+            AddressPattern::Domain(domain) => match &address.host {
+                Some(host) => domain_matches(&host.to_lowercase(), domain),
+                None => false,
+            },
+            AddressPattern::Regex(regex) => address.address().is_some_and(|actual| regex.is_match(&actual)),
+        }
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    match domain.strip_prefix("*.") {
+        Some(parent) => host == parent || host.ends_with(&format!(".{parent}")),
+        None => host == domain,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(name: Option<&str>, address: &str) -> Address {
+        let (mailbox, host) = address.split_once('@').unwrap();
+        Address { name: name.map(str::to_string), mailbox: Some(mailbox.to_string()), host: Some(host.to_string()) }
+    }
+
+    fn pattern(pattern: &str) -> AddressPattern {
+        AddressPattern::try_from(pattern.to_string()).unwrap()
+    }
+
+    #[test]
+    fn matches_exact_addresses_and_names() {
+        let jane = address(Some("Jane Doe"), "Jane.Doe@Caterer.pl");
+        assert!(pattern("jane.doe@caterer.pl").matches(&jane));
+        assert!(!pattern("jane@caterer.pl").matches(&jane));
+        assert!(pattern("Jane Doe").matches(&jane));
+        assert!(!pattern("jane doe").matches(&jane));
+        assert_eq!(jane.to_string(), "Jane Doe <Jane.Doe@Caterer.pl>");
+    }
+
+    #[test]
+    fn matches_domains_and_subdomains() {
+        let domain = pattern("*@caterer.pl");
+        assert!(domain.matches(&address(None, "jane@CATERER.PL")));
+        assert!(!domain.matches(&address(None, "jane@orders.caterer.pl")));
+        assert!(!domain.matches(&address(None, "jane@notcaterer.pl")));
+
+        let subdomains = pattern("*@*.caterer.pl");
+        assert!(subdomains.matches(&address(None, "jane@caterer.pl")));
+        assert!(subdomains.matches(&address(None, "jane@orders.caterer.pl")));
+        assert!(!subdomains.matches(&address(None, "jane@notcaterer.pl")));
+    }
+
+    #[test]
+    fn matches_regexes_on_addresses() {
+        let regex = pattern("/^orders?@caterer\\.pl$/");
+        assert!(regex.matches(&address(None, "order@caterer.pl")));
+        assert!(!regex.matches(&address(Some("orders@caterer.pl"), "orders@caterer.pl.evil.example")));
+    }
+
+    #[test]
+    fn display_names_dont_pass_for_addresses() {
+        let spoofed = address(Some("x@caterer.pl"), "evil@attacker.example");
+        for allowed in ["/@caterer\\.pl$/", "*@caterer.pl", "x@caterer.pl", "*@*.caterer.pl"] {
+            assert!(!pattern(allowed).matches(&spoofed), "{allowed}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(AddressPattern::try_from("/(/".to_string()).unwrap_err().starts_with("invalid sender regex"));
+        assert!(AddressPattern::try_from("*@".to_string()).is_err());
+        assert!(AddressPattern::try_from("*@a@b".to_string()).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use serde::Deserialize;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::mime::Attachment;
+use crate::paths;
+use crate::template::{Context, Template};
+use crate::text::GlobPattern;
+
+/// Which attachments a condition or action is about. Every given criterion has to hold,
+/// empty lists allow anything.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AttachmentFilter {
+    // "application/pdf", or "image/*" for a whole type
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+    // File name globs, e.g. "*.pdf"
+    #[serde(default)]
+    pub names: Vec<GlobPattern>,
+    // Largest decoded size
+    pub max_bytes: Option<u32>,
+}
+
+impl AttachmentFilter {
+    pub fn validate(&self, rule_name: &str, problems: &mut Vec<String>) {
+        for mime_type in &self.mime_types {
+            let valid = match mime_type.split_once('/') {
+                Some((ty, subtype)) => !ty.is_empty() && !subtype.is_empty() && !ty.contains('*'),
+                None => false,
+            };
+            if !valid {
+                problems.push(format!("rule \"{rule_name}\" has invalid attachment MIME type {mime_type}"));
+            }
+        }
+    }
+
+    pub fn matches(&self, attachment: &Attachment) -> bool {
+        let mime_type_allowed = self.mime_types.is_empty() || self.mime_types.iter().any(|allowed| {
+            let allowed = allowed.to_lowercase();
+            match allowed.strip_suffix("/*") {
+                Some(ty) => attachment.mime_type.split('/').next() == Some(ty),
+                None => attachment.mime_type == allowed,
+            }
+        });
+        let name_allowed = self.names.is_empty() || attachment.filename.as_ref()
+            .is_some_and(|filename| self.names.iter().any(|glob| glob.matches(filename)));
+        let size_allowed = self.max_bytes.is_none_or(|max_bytes| attachment.size() <= max_bytes);
+
+        mime_type_allowed && name_allowed && size_allowed
+    }
+}
+
+/// Settings of the save_attachments action.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SaveAttachments {
+    // Where files go, e.g. "~/Invoices/{from_domain}/{year}-{month}"; created when missing
+    pub directory: Template,
+    #[serde(flatten)]
+    pub filter: AttachmentFilter,
+}
+
+// Placeholder value as a single path component: no separators, no "..", nothing hidden
+fn path_component(value: String) -> String {
+    let component = safe_filename(&value.replace(['/', '\\'], "_"));
+    if component.is_empty() {
+        "_".to_string()
+    } else {
+        component
+    }
+}
+
+/// Strips anything that could escape the target directory or upset a file system.
+pub fn safe_filename(name: &str) -> String {
+    // Some clients send the full path the file had on the sender's machine
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = name.chars()
+        .map(|c| if c.is_control() || "<>:\"|?*".contains(c) { '_' } else { c })
+        .collect();
+    let cleaned = cleaned.trim_matches(|c: char| c == '.' || c.is_whitespace());
+
+    // Keep within the usual 255 byte limit, leaving room for a " (n)" suffix
+    let mut end = cleaned.len().min(200);
+    while !cleaned.is_char_boundary(end) {
+        end -= 1;
+    }
+    cleaned[..end].to_string()
+}
+
+impl SaveAttachments {
+    pub fn directory(&self, context: &Context<'_>) -> PathBuf {
+        paths::expand_home(&self.directory.render(context, path_component))
+    }
+}
+
+/// Writes an attachment into the directory without overwriting anything, returns the path used.
+/// A file with the same contents under one of the names it would get counts as saved, so
+/// that saving again, e.g. when the action is retried, doesn't leave copies behind.
+pub fn save(directory: &Path, attachment: &Attachment, data: &[u8]) -> io::Result<PathBuf> {
+    fs::create_dir_all(directory)?;
+
+    let name = attachment.filename.as_deref().map(safe_filename).unwrap_or_default();
+    let name = if name.is_empty() { format!("attachment-{}", attachment.section()) } else { name };
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem.to_string(), format!(".{extension}")),
+        _ => (name.clone(), String::new()),
+    };
+
+    for attempt in 0.. {
+        let candidate = match attempt {
+            0 => directory.join(&name),
+            n => directory.join(format!("{stem} ({n}){extension}")),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(mut file) => {
+                if let Err(e) = file.write_all(data) {
+                    // Half a file would only be taken for a different attachment later
+                    let _ = fs::remove_file(&candidate);
+                    return Err(e);
+                }
+                return Ok(candidate);
+            },
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if has_contents(&candidate, data) {
+                    return Ok(candidate);
+                }
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("attempts never run out");
+}
+
+fn has_contents(path: &Path, data: &[u8]) -> bool {
+    let same_length = fs::metadata(path).is_ok_and(|metadata| metadata.len() == data.len() as u64);
+    same_length && fs::read(path).is_ok_and(|contents| contents == data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime::TransferEncoding;
+
+    fn attachment(mime_type: &str, filename: Option<&str>, octets: u32) -> Attachment {
+        Attachment {
+            path: vec![2],
+            mime_type: mime_type.to_string(),
+            filename: filename.map(str::to_string),
+            encoding: TransferEncoding::Base64,
+            octets,
+        }
+    }
+
+    fn filter(json: &str) -> AttachmentFilter {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn filter_checks_every_criterion() {
+        let pdf = attachment("application/pdf", Some("Menu.PDF"), 4000);
+        assert!(filter("{}").matches(&pdf));
+        assert!(filter(r#"{"mime_types": ["Application/PDF"], "names": ["*.pdf"], "max_bytes": 3000}"#).matches(&pdf));
+        assert!(!filter(r#"{"max_bytes": 2999}"#).matches(&pdf));
+        assert!(!filter(r#"{"names": ["*.doc"]}"#).matches(&pdf));
+        assert!(filter(r#"{"mime_types": ["image/*", "application/*"]}"#).matches(&pdf));
+        assert!(!filter(r#"{"mime_types": ["image/*"]}"#).matches(&pdf));
+        // Name globs can't match a file without a name
+        assert!(!filter(r#"{"names": ["*"]}"#).matches(&attachment("image/png", None, 10)));
+    }
+
+    #[test]
+    fn validates_mime_types() {
+        let mut problems = Vec::new();
+        filter(r#"{"mime_types": ["pdf", "*/*", "image/*", "text/"]}"#).validate("r", &mut problems);
+        assert_eq!(problems.len(), 3);
+    }
+
+    #[test]
+    fn file_names_stay_in_directory() {
+        assert_eq!(safe_filename("C:\\Users\\jane\\menu.pdf"), "menu.pdf");
+        assert_eq!(safe_filename("../../.bashrc"), "bashrc");
+        assert_eq!(safe_filename("a<b>:c?.txt"), "a_b__c_.txt");
+        assert_eq!(safe_filename(".."), "");
+        assert_eq!(safe_filename(&"ą".repeat(150)).len(), 200);
+        assert_eq!(path_component("../x/y".to_string()), "_x_y");
+        assert_eq!(path_component("..".to_string()), "_");
+    }
+
+    #[test]
+    fn saving_never_overwrites_nor_copies() {
+        let directory = std::env::temp_dir().join(format!("imap-listener-test-{}", std::process::id()));
+        let pdf = attachment("application/pdf", Some("menu.pdf"), 10);
+        let unnamed = attachment("application/pdf", None, 10);
+
+        let first = save(&directory, &pdf, b"one").unwrap();
+        let second = save(&directory, &pdf, b"two").unwrap();
+        let third = save(&directory, &unnamed, b"three").unwrap();
+        assert_eq!(first.file_name().unwrap(), "menu.pdf");
+        assert_eq!(second.file_name().unwrap(), "menu (1).pdf");
+        assert_eq!(third.file_name().unwrap(), "attachment-2");
+        assert_eq!(fs::read(&first).unwrap(), b"one");
+
+        // Saving the same again finds the earlier files
+        assert_eq!(save(&directory, &pdf, b"one").unwrap(), first);
+        assert_eq!(save(&directory, &pdf, b"two").unwrap(), second);
+        assert_eq!(save(&directory, &unnamed, b"three").unwrap(), third);
+        assert_eq!(save(&directory, &pdf, b"tw").unwrap().file_name().unwrap(), "menu (2).pdf");
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 4);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
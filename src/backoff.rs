@@ -0,0 +1,199 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::Duration;
+use log::{info, warn};
+
+use crate::oauth2::StaleToken;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct BackoffConfig {
+    // Delay after the first failure
+    pub initial_secs: u64,
+    // Delays never grow above this
+    pub max_secs: u64,
+    // Each consecutive failure multiplies the delay by this
+    pub multiplier: f64,
+    // Delays are randomly shortened or lengthened by up to this fraction
+    pub jitter: f64,
+    // Consecutive failures after which the circuit opens
+    pub failure_threshold: u32,
+    // How long an open circuit waits before a trial connection
+    pub open_secs: u64,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> BackoffConfig {
+        BackoffConfig {
+            initial_secs: 10,
+            max_secs: 600,
+            multiplier: 2.0,
+            jitter: 0.2,
+            failure_threshold: 8,
+            open_secs: 1800,
+        }
+    }
+}
+
+impl BackoffConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.initial_secs == 0 || self.max_secs < self.initial_secs {
+            problems.push("backoff.initial_secs has to be between 1 and backoff.max_secs".to_string());
+        }
+        if self.multiplier < 1.0 {
+            problems.push("backoff.multiplier can't be less than 1".to_string());
+        }
+        if !(0.0..1.0).contains(&self.jitter) {
+            problems.push("backoff.jitter has to be at least 0 and less than 1".to_string());
+        }
+        if self.failure_threshold == 0 {
+            problems.push("backoff.failure_threshold has to be greater than 0".to_string());
+        }
+        problems
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    // Network trouble, server restarts and the like, worth retrying soon
+    Transient,
+    // Server rejected us (e.g. wrong password), retrying soon won't help
+    Permanent,
+}
+
+/// Login failures where the server answered NO or BAD are permanent, the rest transient.
+/// A rejected stored OAuth2 token is transient, as a fresh one may still be accepted.
+pub fn login_failure_kind(error: &(dyn Error + 'static)) -> FailureKind {
+    if error.is::<StaleToken>() {
+        return FailureKind::Transient;
+    }
+    match error.downcast_ref::<imap::Error>() {
+        Some(imap::Error::No(_)) | Some(imap::Error::Bad(_)) => FailureKind::Permanent,
+        _ => FailureKind::Transient,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    // Connecting normally
+    Closed,
+    // Too many failures, waiting a long time before trying again
+    Open,
+    // Trial connection after the circuit was open
+    HalfOpen,
+}
+
+/// Decides how long to wait before reconnecting.
+pub struct Backoff {
+    config: BackoffConfig,
+    consecutive_failures: u32,
+    circuit: CircuitState,
+}
+
+impl Backoff {
+    pub fn new(config: BackoffConfig) -> Backoff {
+        Backoff { config, consecutive_failures: 0, circuit: CircuitState::Closed }
+    }
+
+    pub fn circuit(&self) -> CircuitState {
+        self.circuit
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    /// Connection works again.
+    pub fn success(&mut self) {
+        if self.circuit != CircuitState::Closed {
+            info!("Circuit closed after {} failures", self.consecutive_failures);
+        }
+        self.consecutive_failures = 0;
+        self.circuit = CircuitState::Closed;
+    }
+
+    /// Records a failure and returns how long to wait before the next attempt.
+    pub fn failure(&mut self, kind: FailureKind) -> Duration {
+        self.consecutive_failures += 1;
+
+        let open = kind == FailureKind::Permanent
+            || self.circuit == CircuitState::HalfOpen
+            || self.consecutive_failures >= self.config.failure_threshold;
+        if open {
+            if self.circuit != CircuitState::Open {
+                warn!("Circuit opened after {} failures ({kind:?})", self.consecutive_failures);
+            }
+            self.circuit = CircuitState::Open;
+            return self.jittered(self.config.open_secs as f64);
+        }
+
+        let exponent = self.consecutive_failures.saturating_sub(1) as i32;
+        let delay = self.config.initial_secs as f64 * self.config.multiplier.powi(exponent);
+        self.jittered(delay.min(self.config.max_secs as f64))
+    }
+
+    /// Called when the wait after an open circuit is over and a trial connection starts.
+    pub fn attempt(&mut self) {
+        if self.circuit == CircuitState::Open {
+            info!("Circuit half-open, trying to connect");
+            self.circuit = CircuitState::HalfOpen;
+        }
+    }
+
+    fn jittered(&self, secs: f64) -> Duration {
+        let factor = if self.config.jitter > 0.0 {
+            rand::thread_rng().gen_range(1.0 - self.config.jitter..1.0 + self.config.jitter)
+        } else {
+            1.0
+        };
+        Duration::from_secs_f64(secs * factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> BackoffConfig {
+        BackoffConfig { initial_secs: 10, max_secs: 60, jitter: 0.0, failure_threshold: 5, ..BackoffConfig::default() }
+    }
+
+    #[test]
+    fn rejected_stored_token_is_transient() {
+        let error: Box<dyn Error> = StaleToken(imap::Error::ConnectionLost).into();
+        assert_eq!(login_failure_kind(error.as_ref()), FailureKind::Transient);
+        let error: Box<dyn Error> = imap::Error::ConnectionLost.into();
+        assert_eq!(login_failure_kind(error.as_ref()), FailureKind::Transient);
+    }
+
+    #[test]
+    fn delays_grow_up_to_max_then_circuit_opens() {
+        let mut backoff = Backoff::new(config());
+        let delays: Vec<u64> = (0..4).map(|_| backoff.failure(FailureKind::Transient).as_secs()).collect();
+        assert_eq!(delays, [10, 20, 40, 60]);
+        assert_eq!(backoff.circuit(), CircuitState::Closed);
+        assert_eq!(backoff.failure(FailureKind::Transient).as_secs(), 1800);
+        assert_eq!(backoff.circuit(), CircuitState::Open);
+
+        // A failed trial connection opens the circuit again right away
+        backoff.attempt();
+        assert_eq!(backoff.circuit(), CircuitState::HalfOpen);
+        backoff.failure(FailureKind::Transient);
+        assert_eq!(backoff.circuit(), CircuitState::Open);
+
+        backoff.attempt();
+        backoff.success();
+        assert_eq!(backoff.circuit(), CircuitState::Closed);
+        assert_eq!(backoff.failure(FailureKind::Transient).as_secs(), 10);
+    }
+
+    #[test]
+    fn permanent_failure_opens_circuit() {
+        let mut backoff = Backoff::new(config());
+        assert_eq!(backoff.failure(FailureKind::Permanent).as_secs(), 1800);
+        assert_eq!(backoff.circuit(), CircuitState::Open);
+    }
+}
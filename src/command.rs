@@ -0,0 +1,221 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+
+use crate::actions::{Action, ActionContext, Outcome};
+use crate::error::ActionError;
+use crate::template::Template;
+
+// How often a running command is checked for having finished
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Commands running right now, across all workers
+static RUNNING: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+
+/// Settings of the run_command action. Every text is a template, e.g.
+/// `{"program": "notify.sh", "args": ["{from}", "{subject}"]}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RunCommand {
+    pub program: Template,
+    #[serde(default)]
+    pub args: Vec<Template>,
+    // Added to the environment the listener runs in
+    #[serde(default)]
+    pub env: BTreeMap<String, Template>,
+    // Written to the standard input of the program, which gets nothing otherwise
+    pub stdin: Option<Template>,
+    // Program is killed when it runs longer than this
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    // Run program as a "sh -c" script, with placeholder values quoted and the args as
+    // $1, $2...; by default the program is started directly and nothing is interpreted
+    #[serde(default)]
+    pub shell: bool,
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+// Single quotes a value for sh, so that it can't break out of its argument
+fn shell_quote(value: String) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+// Holds one of the slots for running commands while alive
+struct Slot;
+
+impl Slot {
+    fn acquire(limit: usize) -> Slot {
+        let (running, freed) = &RUNNING;
+        let mut running = freed.wait_while(running.lock().unwrap(), |running| *running >= limit).unwrap();
+        *running += 1;
+        Slot
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let (running, freed) = &RUNNING;
+        *running.lock().unwrap() -= 1;
+        freed.notify_one();
+    }
+}
+
+// Starts the program in a process group of its own, so that killing the group on a
+// timeout also gets whatever it started, e.g. the commands of a shell script
+fn own_process_group(command: &mut Command) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+}
+
+// Waits for the program until the timeout, then kills its whole process group
+fn wait_with_timeout(child: &mut Child, program: &str, timeout: Duration) -> Result<ExitStatus, ActionError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(status),
+            Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+            Ok(None) => {
+                #[cfg(unix)]
+                // SAFETY: only sends a signal; the group id is the pid of our child, which
+                // isn't reaped yet and so can't have been reused
+                unsafe {
+                    libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+                }
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(ActionError::Failed(format!("{program} killed after {}s", timeout.as_secs()).into()));
+            },
+            Err(e) => return Err(ActionError::Failed(format!("failed to wait for {program}: {e}").into())),
+        }
+    }
+}
+
+/// Runs the program in the background, logging how it ended.
+pub fn spawn_background(mut command: Command) -> std::io::Result<()> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let mut child = command.spawn()?;
+    thread::spawn(move || {
+        match child.wait() {
+            Ok(status) if !status.success() => warn!("{program} exited with {status}"),
+            Ok(_) => {},
+            Err(e) => warn!("Failed to wait for {program}: {e}"),
+        }
+    });
+    Ok(())
+}
+
+impl RunCommand {
+    pub fn validate(&self, rule_name: &str, problems: &mut Vec<String>) {
+        if self.program.is_empty() {
+            problems.push(format!("rule \"{rule_name}\" runs a command without program"));
+        }
+        if self.timeout_secs == 0 {
+            problems.push(format!("rule \"{rule_name}\" runs a command with timeout_secs of 0"));
+        }
+    }
+
+    fn build(&self, context: &ActionContext<'_>) -> Command {
+        let values = context.template_context();
+        let args = self.args.iter().map(|arg| arg.render(&values, |value| value));
+
+        let mut command = if self.shell {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(self.program.render(&values, shell_quote)).arg("imap-listener").args(args);
+            command
+        } else {
+            let mut command = Command::new(self.program.render(&values, |value| value));
+            command.args(args);
+            command
+        };
+        command.envs(self.env.iter().map(|(name, value)| (name, value.render(&values, |value| value))));
+        command.stdin(if self.stdin.is_some() { Stdio::piped() } else { Stdio::null() });
+        own_process_group(&mut command);
+        command
+    }
+}
+
+impl Action for RunCommand {
+    fn name(&self) -> &'static str {
+        "run_command"
+    }
+
+    // Waits for the program to finish, so that a failure is subject to the error policy
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        let mut command = self.build(context);
+        let program = command.get_program().to_string_lossy().to_string();
+        let stdin = self.stdin.as_ref().map(|stdin| stdin.render(&context.template_context(), |value| value));
+
+        let _slot = Slot::acquire(context.config.max_running_commands);
+        let mut child = command.spawn().map_err(|e| ActionError::Failed(format!("failed to run {program}: {e}").into()))?;
+        if let (Some(mut pipe), Some(stdin)) = (child.stdin.take(), stdin) {
+            // Written from another thread, a program that doesn't read it mustn't block us
+            thread::spawn(move || {
+                if let Err(e) = pipe.write_all(stdin.as_bytes()) {
+                    warn!("Failed to write standard input of command: {e}");
+                }
+            });
+        }
+
+        let status = wait_with_timeout(&mut child, &program, Duration::from_secs(self.timeout_secs))?;
+        context.watchdog.feed();
+
+        if !status.success() {
+            return Err(ActionError::Failed(format!("{program} exited with {status}").into()));
+        }
+        info!("{program} exited with {status}");
+        Ok(Outcome::Kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_quote_keeps_values_one_argument() {
+        assert_eq!(shell_quote("plain".to_string()), "'plain'");
+        assert_eq!(shell_quote("it's $(rm -rf ~)".to_string()), r"'it'\''s $(rm -rf ~)'");
+
+        let quoted = shell_quote("a'b \"c\" $HOME `x`;".to_string());
+        let output = Command::new("sh").arg("-c").arg(format!("printf %s {quoted}")).output().unwrap();
+        assert_eq!(output.stdout, b"a'b \"c\" $HOME `x`;");
+    }
+
+    #[test]
+    fn timeout_kills_whole_process_group() {
+        let pid_file = std::env::temp_dir().join(format!("imap-listener-test-{}.pid", std::process::id()));
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!("sleep 30 & echo $! > {}; wait", pid_file.display()));
+        own_process_group(&mut command);
+        let mut child = command.spawn().unwrap();
+
+        assert!(wait_with_timeout(&mut child, "sh", Duration::from_secs(1)).is_err());
+        let pid: libc::pid_t = std::fs::read_to_string(&pid_file).unwrap().trim().parse().unwrap();
+        std::fs::remove_file(&pid_file).unwrap();
+        // The orphaned sleep is reaped by init once killed
+        let gone = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(20));
+            // SAFETY: signal 0 only checks whether the process exists
+            unsafe { libc::kill(pid, 0) != 0 }
+        });
+        assert!(gone, "sleep started by the script is still running");
+    }
+
+    #[test]
+    fn waits_for_exit_status() {
+        let mut child = Command::new("sh").arg("-c").arg("exit 3").spawn().unwrap();
+        let status = wait_with_timeout(&mut child, "sh", Duration::from_secs(5)).unwrap();
+        assert_eq!(status.code(), Some(3));
+    }
+}
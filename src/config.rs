@@ -0,0 +1,594 @@
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::backoff::BackoffConfig;
+use crate::oauth2::{Mechanism, OAuth2Config};
+use crate::password::PasswordSource;
+use crate::paths;
+use crate::rules::{self, Rule};
+use crate::notification::NotificationConfig;
+use crate::smtp::{Security, SmtpConfig};
+use crate::sound::SoundConfig;
+use crate::webhook::WebhookConfig;
+use crate::verification::VerificationConfig;
+use crate::Opt;
+
+/// Contents of the `--config` file. Every value is optional here, so that it
+/// can be supplied on the command line instead.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    // A single account can be given at the top level...
+    server: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    auth: Option<AuthMethod>,
+    oauth2: Option<OAuth2Config>,
+    password: Option<String>,
+    password_env: Option<String>,
+    password_file: Option<String>,
+    password_command: Option<String>,
+    mailbox: Option<String>,
+    // ...or any number of them here
+    accounts: Option<Vec<FileAccount>>,
+    refresh_rate: Option<u64>,
+    idle_renew_secs: Option<u64>,
+    watchdog_secs: Option<u64>,
+    #[serde(default)]
+    backoff: BackoffConfig,
+    allowed_people: Option<String>,
+    triggering_subjects: Option<String>,
+    mail_expiration_secs: Option<u32>,
+    body_max_bytes: Option<u32>,
+    max_running_commands: Option<usize>,
+    audio_file: Option<String>,
+    #[serde(default)]
+    sound: SoundConfig,
+    #[serde(default)]
+    webhooks: WebhookConfig,
+    #[serde(default)]
+    notifications: NotificationConfig,
+    smtp: Option<FileSmtp>,
+    rules_file: Option<String>,
+    rules: Option<Vec<Rule>>,
+    state_file: Option<String>,
+    status_file: Option<String>,
+    quarantine_folder: Option<String>,
+    #[serde(default)]
+    verification: VerificationConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileAccount {
+    name: String,
+    server: String,
+    port: Option<u16>,
+    username: String,
+    auth: Option<AuthMethod>,
+    oauth2: Option<OAuth2Config>,
+    password: Option<String>,
+    password_env: Option<String>,
+    password_file: Option<String>,
+    password_command: Option<String>,
+    mailboxes: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileSmtp {
+    server: String,
+    port: Option<u16>,
+    #[serde(default)]
+    security: Security,
+    username: Option<String>,
+    password: Option<String>,
+    password_env: Option<String>,
+    password_file: Option<String>,
+    password_command: Option<String>,
+    from: String,
+    timeout_secs: Option<u64>,
+    replies_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    Password,
+    Xoauth2,
+    Oauthbearer,
+}
+
+#[derive(Debug)]
+pub enum Auth {
+    Password(PasswordSource),
+    OAuth2(Mechanism, OAuth2Config),
+}
+
+/// One IMAP login, with every mailbox that is watched on it.
+#[derive(Debug)]
+pub struct Account {
+    // Used in logs to tell the workers apart
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    pub username: String,
+    pub auth: Auth,
+    pub mailboxes: Vec<String>,
+}
+
+/// Final settings, after command line flags were laid over the config file.
+#[derive(Debug)]
+pub struct Config {
+    pub accounts: Vec<Account>,
+    // Polling interval for servers without IDLE
+    pub refresh_rate: u64,
+    pub idle_renew_secs: u64,
+    pub watchdog_secs: u64,
+    // Delays between reconnection attempts
+    pub backoff: BackoffConfig,
+    pub allowed_people: String,
+    pub triggering_subjects: String,
+    pub mail_expiration_secs: u32,
+    // Most bytes of mail text fetched for body conditions and local DKIM checks
+    pub body_max_bytes: u32,
+    // Most commands of run_command actions running at once, over all mailboxes
+    pub max_running_commands: usize,
+    pub audio_file: String,
+    // How sounds are played, and their default volume and repeats
+    pub sound: SoundConfig,
+    // Where failed webhook requests wait, and how they are retried
+    pub webhooks: WebhookConfig,
+    // What notification buttons do and how long notifications stay
+    pub notifications: NotificationConfig,
+    // Server forward and auto_reply actions send through
+    pub smtp: Option<SmtpConfig>,
+    pub rules_file: Option<String>,
+    // Rules given inline in the config file
+    pub rules: Option<Vec<Rule>>,
+    // Where processed mails are remembered between restarts
+    pub state_file: String,
+    // Where connection state of workers is published
+    pub status_file: String,
+    // Mails that can't be handled are moved here instead of being skipped
+    pub quarantine_folder: Option<String>,
+    // Which headers and keys are trusted to tell where a mail comes from
+    pub verification: VerificationConfig,
+}
+
+/// All problems found in the configuration, reported together.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration: {}", self.0.join("; "))
+    }
+}
+
+impl Error for ConfigError {}
+
+fn read_config_file(filepath: &str) -> Result<FileConfig, Box<dyn Error>> {
+    let contents = fs::read_to_string(filepath)?;
+
+    let extension = Path::new(filepath).extension().and_then(|e| e.to_str()).unwrap_or_default();
+    let file_config = match extension {
+        // Rules are written as `{all: [...]}` maps in yaml too, not as `!all` tags
+        "yaml" | "yml" => serde_yaml::with::singleton_map_recursive::deserialize(
+            serde_yaml::Deserializer::from_str(&contents),
+        )?,
+        _ => toml::from_str(&contents)?,
+    };
+
+    Ok(file_config)
+}
+
+struct PasswordSources {
+    password: Option<String>,
+    password_env: Option<String>,
+    password_file: Option<String>,
+    password_command: Option<String>,
+}
+
+impl PasswordSources {
+    fn any(&self) -> bool {
+        self.password.is_some() || self.password_env.is_some()
+            || self.password_file.is_some() || self.password_command.is_some()
+    }
+}
+
+fn build_auth(
+    method: Option<AuthMethod>,
+    oauth2: Option<OAuth2Config>,
+    sources: PasswordSources,
+    problems: &mut Vec<String>,
+) -> Auth {
+    match method.unwrap_or(AuthMethod::Password) {
+        AuthMethod::Password => {
+            let source = PasswordSource::choose(
+                sources.password,
+                sources.password_env,
+                sources.password_file,
+                sources.password_command,
+            );
+            Auth::Password(source.unwrap_or_else(|problem| {
+                problems.push(problem);
+                PasswordSource::Plain(String::new())
+            }))
+        },
+        method => {
+            let mechanism = if method == AuthMethod::Xoauth2 { Mechanism::Xoauth2 } else { Mechanism::Oauthbearer };
+            let oauth2 = oauth2.unwrap_or_else(|| {
+                problems.push("oauth2 section is required for xoauth2 and oauthbearer auth".to_string());
+                OAuth2Config {
+                    token_url: String::new(),
+                    client_id: String::new(),
+                    client_secret: None,
+                    scope: None,
+                    refresh_token: None,
+                    token_file: String::new(),
+                }
+            });
+            Auth::OAuth2(mechanism, oauth2)
+        },
+    }
+}
+
+impl Config {
+    /// Reads the config file (if any), applies command line overrides and validates the result.
+    pub fn load(opt: &Opt) -> Result<Config, ConfigError> {
+        let file = match &opt.config {
+            Some(filepath) => read_config_file(filepath)
+                .map_err(|e| ConfigError(vec![format!("failed to read {filepath}: {e}")]))?,
+            None => FileConfig::default(),
+        };
+
+        let mut problems = Vec::new();
+
+        let cli_sources = PasswordSources {
+            password: opt.password.clone(),
+            password_env: opt.password_env.clone(),
+            password_file: opt.password_file.clone(),
+            password_command: opt.password_command.clone(),
+        };
+
+        let accounts = match file.accounts {
+            Some(file_accounts) => {
+                let top_level_account = opt.server.is_some() || opt.username.is_some() || opt.port.is_some()
+                    || opt.mailbox.is_some() || cli_sources.any() || file.server.is_some()
+                    || file.username.is_some() || file.port.is_some() || file.mailbox.is_some()
+                    || file.auth.is_some() || file.oauth2.is_some() || file.password.is_some()
+                    || file.password_env.is_some() || file.password_file.is_some()
+                    || file.password_command.is_some();
+                if top_level_account {
+                    problems.push("top-level account settings can't be combined with accounts".to_string());
+                }
+
+                file_accounts.into_iter().map(|account| {
+                    let sources = PasswordSources {
+                        password: account.password,
+                        password_env: account.password_env,
+                        password_file: account.password_file,
+                        password_command: account.password_command,
+                    };
+                    Account {
+                        auth: build_auth(account.auth, account.oauth2, sources, &mut problems),
+                        name: account.name,
+                        server: account.server,
+                        port: account.port.unwrap_or(993),
+                        username: account.username,
+                        mailboxes: account.mailboxes.unwrap_or_else(|| vec!["INBOX".to_string()]),
+                    }
+                }).collect()
+            },
+            None => {
+                // A password source given on the command line replaces any source from the file
+                let sources = if cli_sources.any() {
+                    cli_sources
+                } else {
+                    PasswordSources {
+                        password: file.password,
+                        password_env: file.password_env,
+                        password_file: file.password_file,
+                        password_command: file.password_command,
+                    }
+                };
+                let auth = build_auth(file.auth, file.oauth2, sources, &mut problems);
+
+                let mut required = |name: &str, value: Option<String>| {
+                    value.unwrap_or_else(|| {
+                        problems.push(format!("missing {name}"));
+                        String::new()
+                    })
+                };
+                let server = required("server", opt.server.clone().or(file.server));
+                let username = required("username", opt.username.clone().or(file.username));
+
+                vec![Account {
+                    name: username.clone(),
+                    server,
+                    port: opt.port.or(file.port).unwrap_or(993),
+                    username,
+                    auth,
+                    mailboxes: vec![opt.mailbox.clone().or(file.mailbox).unwrap_or_else(|| "INBOX".to_string())],
+                }]
+            },
+        };
+
+        let smtp = file.smtp.map(|smtp| {
+            let sources = PasswordSources {
+                password: smtp.password,
+                password_env: smtp.password_env,
+                password_file: smtp.password_file,
+                password_command: smtp.password_command,
+            };
+            let credentials = match smtp.username {
+                Some(username) => {
+                    let source = PasswordSource::choose(
+                        sources.password,
+                        sources.password_env,
+                        sources.password_file,
+                        sources.password_command,
+                    );
+                    source.map(|source| (username, source))
+                        .map_err(|problem| problems.push(format!("smtp: {problem}")))
+                        .ok()
+                },
+                None => {
+                    if sources.any() {
+                        problems.push("smtp password is given without smtp.username".to_string());
+                    }
+                    None
+                },
+            };
+            SmtpConfig {
+                server: smtp.server,
+                port: smtp.port.unwrap_or(smtp.security.default_port()),
+                security: smtp.security,
+                credentials,
+                from: smtp.from,
+                timeout_secs: smtp.timeout_secs.unwrap_or(30),
+                replies_file: smtp.replies_file.unwrap_or_else(|| "imap_listener_replies.json".to_string()),
+            }
+        });
+
+        let config = Config {
+            accounts,
+            refresh_rate: opt.refresh_rate.or(file.refresh_rate).unwrap_or(10),
+            idle_renew_secs: opt.idle_renew_secs.or(file.idle_renew_secs).unwrap_or(25 * 60),
+            watchdog_secs: opt.watchdog_secs.or(file.watchdog_secs).unwrap_or(30 * 60),
+            backoff: file.backoff,
+            allowed_people: opt.allowed_people.clone().or(file.allowed_people)
+                .unwrap_or_else(|| "allowed_people.json".to_string()),
+            triggering_subjects: opt.triggering_subjects.clone().or(file.triggering_subjects)
+                .unwrap_or_else(|| "triggering_subjects.json".to_string()),
+            mail_expiration_secs: opt.mail_expiration_secs.or(file.mail_expiration_secs).unwrap_or(180),
+            body_max_bytes: opt.body_max_bytes.or(file.body_max_bytes).unwrap_or(256 * 1024),
+            max_running_commands: opt.max_running_commands.or(file.max_running_commands).unwrap_or(4),
+            audio_file: opt.audio_file.clone().or(file.audio_file)
+                .unwrap_or_else(|| "~/Music/kanapkiv2.wav".to_string()),
+            sound: file.sound,
+            webhooks: file.webhooks,
+            notifications: file.notifications,
+            smtp,
+            rules_file: opt.rules.clone().or(file.rules_file),
+            // --rules on the command line replaces rules given inline in the file
+            rules: if opt.rules.is_some() { None } else { file.rules },
+            state_file: opt.state_file.clone().or(file.state_file)
+                .unwrap_or_else(|| "imap_listener_state.json".to_string()),
+            status_file: opt.status_file.clone().or(file.status_file)
+                .unwrap_or_else(|| "imap_listener_status.json".to_string()),
+            quarantine_folder: opt.quarantine_folder.clone().or(file.quarantine_folder),
+            verification: file.verification,
+        };
+
+        problems.extend(config.validate());
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.accounts.is_empty() {
+            problems.push("no accounts defined".to_string());
+        }
+        for (index, account) in self.accounts.iter().enumerate() {
+            if self.accounts[..index].iter().any(|other| other.name == account.name) {
+                problems.push(format!("account name \"{}\" is used more than once", account.name));
+            }
+            if account.mailboxes.is_empty() {
+                problems.push(format!("account \"{}\" has no mailboxes", account.name));
+            }
+            if account.mailboxes.iter().any(|mailbox| mailbox.is_empty()) {
+                problems.push(format!("account \"{}\" has an empty mailbox name", account.name));
+            }
+            for (index, mailbox) in account.mailboxes.iter().enumerate() {
+                // INBOX is the one name IMAP treats case-insensitively
+                let same = |other: &String| other == mailbox
+                    || (other.eq_ignore_ascii_case("INBOX") && mailbox.eq_ignore_ascii_case("INBOX"));
+                if account.mailboxes[..index].iter().any(same) {
+                    problems.push(format!("account \"{}\" lists mailbox \"{mailbox}\" more than once", account.name));
+                }
+            }
+            if let Auth::Password(source) = &account.auth {
+                if let Err(problem) = source.validate() {
+                    problems.push(format!("account \"{}\": {problem}", account.name));
+                }
+            }
+        }
+        if self.refresh_rate == 0 {
+            problems.push("refresh_rate has to be greater than 0".to_string());
+        }
+        if self.idle_renew_secs == 0 || self.idle_renew_secs > 29 * 60 {
+            problems.push("idle_renew_secs has to be between 1 and 1740".to_string());
+        }
+        if self.watchdog_secs <= self.idle_renew_secs || self.watchdog_secs <= self.refresh_rate {
+            problems.push("watchdog_secs has to be greater than idle_renew_secs and refresh_rate".to_string());
+        }
+        if self.body_max_bytes == 0 {
+            problems.push("body_max_bytes has to be greater than 0".to_string());
+        }
+        if self.max_running_commands == 0 {
+            problems.push("max_running_commands has to be greater than 0".to_string());
+        }
+        problems.extend(self.backoff.validate());
+        problems.extend(self.verification.validate());
+        problems.extend(self.sound.validate());
+        problems.extend(self.webhooks.validate());
+        problems.extend(self.notifications.validate());
+        if let Some(smtp) = &self.smtp {
+            problems.extend(smtp.validate());
+        }
+        if let Err(e) = paths::expand(&self.audio_file) {
+            problems.push(format!("audio_file: {e}"));
+        }
+        if self.quarantine_folder.as_deref().is_some_and(|folder| folder.split('/').any(str::is_empty)) {
+            problems.push("quarantine_folder can't have an empty level".to_string());
+        }
+        if self.rules.is_some() && self.rules_file.is_some() {
+            problems.push("rules and rules_file can't be used together".to_string());
+        }
+
+        match self.load_rules() {
+            Ok(rules) => problems.extend(self.check_rules(&rules)),
+            Err(e) => problems.push(format!("failed to load rules: {e}")),
+        }
+
+        problems
+    }
+
+    /// Problems of the rules on their own and together with the rest of the configuration.
+    pub fn check_rules(&self, rules: &[Rule]) -> Vec<String> {
+        let mut problems = rules::validate_rules(rules);
+        if rules::rules_need_verification(rules) && !self.verification.is_configured() {
+            problems.push("rules use verified conditions, but nothing in verification is trusted".to_string());
+        }
+        if rules::rules_send_mail(rules) && self.smtp.is_none() {
+            problems.push("rules forward or answer mail, but there's no smtp section".to_string());
+        }
+        problems
+    }
+
+    /// Files the rules are read from, none when they are given inline.
+    pub fn rule_files(&self) -> Vec<&str> {
+        if self.rules.is_some() {
+            return Vec::new();
+        }
+        match &self.rules_file {
+            Some(rules_file) => vec![rules_file.as_str()],
+            None => vec![self.allowed_people.as_str(), self.triggering_subjects.as_str()],
+        }
+    }
+
+    /// Rules from the config file, the rules file, or built from the legacy lists, in that order.
+    pub fn load_rules(&self) -> Result<Vec<Rule>, Box<dyn Error>> {
+        if let Some(rules) = &self.rules {
+            return Ok(rules.clone());
+        }
+        if let Some(rules_file) = &self.rules_file {
+            return rules::load_rules(rules_file);
+        }
+
+        let allowed_people = rules::parse_json_to_vector(&self.allowed_people)?;
+        let triggering_subjects = rules::parse_json_to_vector(&self.triggering_subjects)?;
+        rules::legacy_rules(allowed_people, triggering_subjects, self.mail_expiration_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    // Loads the given toml as the config file, with extra command line flags
+    fn load(name: &str, toml: &str, flags: &[&str]) -> Result<Config, ConfigError> {
+        let path = std::env::temp_dir().join(format!("imap-listener-config-{name}-{}.toml", std::process::id()));
+        fs::write(&path, toml).unwrap();
+        let mut args = vec!["idle", "--config", path.to_str().unwrap()];
+        args.extend(flags);
+        let config = Config::load(&Opt::from_iter(args));
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    const RULES: &str = r#"
+        [[rules]]
+        name = "catering"
+        condition = { sender = ["*@caterer.pl"] }
+        actions = ["mark_read"]
+    "#;
+
+    #[test]
+    fn command_line_overrides_file() {
+        let toml = format!("server = \"imap.example.com\"\nusername = \"jane\"\npassword = \"secret\"\nrefresh_rate = 5\n{RULES}");
+        let config = load("override", &toml, &["--port", "1993", "--refresh-rate", "7"]).unwrap();
+        let account = &config.accounts[0];
+        assert_eq!((account.server.as_str(), account.port), ("imap.example.com", 1993));
+        assert_eq!(account.mailboxes, ["INBOX"]);
+        assert!(matches!(&account.auth, Auth::Password(PasswordSource::Plain(password)) if password == "secret"));
+        assert_eq!(config.refresh_rate, 7);
+        assert_eq!(config.load_rules().unwrap()[0].name, "catering");
+    }
+
+    #[test]
+    fn reports_all_problems_at_once() {
+        let toml = r#"
+            refresh_rate = 0
+            idle_renew_secs = 3600
+            body_max_bytes = 0
+            quarantine_folder = "Junk//Menus"
+
+            [[accounts]]
+            name = "work"
+            server = "imap.example.com"
+            username = "jane"
+            password = "secret"
+            password_env = "WORK_PASSWORD"
+            mailboxes = ["INBOX", "inbox"]
+
+            [[accounts]]
+            name = "work"
+            server = "imap.example.com"
+            username = "jane"
+            password = "secret"
+            mailboxes = []
+
+            [[rules]]
+            name = "catering"
+            condition = { any = [] }
+            actions = [{ forward = { to = ["team@example.com"] } }]
+        "#;
+        let ConfigError(problems) = load("problems", toml, &[]).unwrap_err();
+        assert_eq!(problems, [
+            "only one of password, password_env, password_file and password_command can be used",
+            "account \"work\" lists mailbox \"inbox\" more than once",
+            "account name \"work\" is used more than once",
+            "account \"work\" has no mailboxes",
+            "refresh_rate has to be greater than 0",
+            "idle_renew_secs has to be between 1 and 1740",
+            "watchdog_secs has to be greater than idle_renew_secs and refresh_rate",
+            "body_max_bytes has to be greater than 0",
+            "quarantine_folder can't have an empty level",
+            "rule \"catering\" has an empty all/any condition",
+            "rules forward or answer mail, but there's no smtp section",
+        ]);
+    }
+
+    #[test]
+    fn reports_unreadable_files() {
+        let ConfigError(problems) = load("syntax", "server = ", &[]).unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("failed to read "), "{}", problems[0]);
+
+        let ConfigError(problems) = load("unknown", "sever = \"imap.example.com\"", &[]).unwrap_err();
+        assert!(problems[0].contains("unknown field `sever`"), "{}", problems[0]);
+    }
+}
@@ -0,0 +1,162 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::backoff::{self, FailureKind};
+
+/// Why a single mail couldn't be handled.
+#[derive(Debug)]
+pub enum MessageError {
+    // Mail disappeared before it could be fetched
+    NotFound,
+    // Server sent no envelope for the mail
+    MissingEnvelope,
+    // Neither the Date header nor INTERNALDATE could be parsed
+    InvalidDate,
+    // Server refused a command for this mail
+    Rejected(imap::Error),
+}
+
+#[derive(Debug)]
+pub enum ListenerError {
+    // Connection broke or the server misbehaves; the worker reconnects
+    Connection(imap::Error),
+    // Credentials couldn't be obtained or weren't accepted
+    Login(Box<dyn Error>),
+    // Watched mailbox couldn't be selected
+    Mailbox(imap::Error),
+    // A single mail failed; other mails are processed normally
+    Message { uid: u32, error: MessageError },
+}
+
+/// Why an action of a rule failed.
+#[derive(Debug)]
+pub enum ActionError {
+    // Connection is in trouble; the worker reconnects and handles the mail again
+    Listener(ListenerError),
+    // Only this action failed, its error policy decides what happens next
+    Failed(Box<dyn Error>),
+}
+
+impl ListenerError {
+    /// Sorts an error of a command issued for one mail: refusals only concern that
+    /// mail, anything else means the connection is in trouble.
+    pub fn for_message(uid: u32, error: imap::Error) -> ListenerError {
+        match error {
+            imap::Error::No(_) | imap::Error::Bad(_) => ListenerError::Message { uid, error: MessageError::Rejected(error) },
+            error => ListenerError::Connection(error),
+        }
+    }
+
+    pub fn failure_kind(&self) -> FailureKind {
+        match self {
+            ListenerError::Login(error) => backoff::login_failure_kind(error.as_ref()),
+            ListenerError::Mailbox(imap::Error::No(_)) | ListenerError::Mailbox(imap::Error::Bad(_)) => {
+                FailureKind::Permanent
+            },
+            _ => FailureKind::Transient,
+        }
+    }
+}
+
+impl ActionError {
+    /// Sorts an error of a command issued for one mail like `ListenerError::for_message`.
+    pub fn imap(uid: u32, error: imap::Error) -> ActionError {
+        ListenerError::for_message(uid, error).into()
+    }
+}
+
+impl From<ListenerError> for ActionError {
+    // Trouble with the mail alone fails the action, anything else needs a reconnect
+    fn from(error: ListenerError) -> ActionError {
+        match error {
+            ListenerError::Message { error, .. } => ActionError::Failed(error.into()),
+            error => ActionError::Listener(error),
+        }
+    }
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::NotFound => f.write_str("mail not found"),
+            MessageError::MissingEnvelope => f.write_str("server sent no envelope"),
+            MessageError::InvalidDate => f.write_str("mail has no valid date"),
+            MessageError::Rejected(e) => write!(f, "server rejected command: {e}"),
+        }
+    }
+}
+
+impl fmt::Display for ListenerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerError::Connection(e) => write!(f, "connection failed: {e}"),
+            ListenerError::Login(e) => write!(f, "login failed: {e}"),
+            ListenerError::Mailbox(e) => write!(f, "failed to select mailbox: {e}"),
+            ListenerError::Message { uid, error } => write!(f, "failed to handle mail of UID {uid}: {error}"),
+        }
+    }
+}
+
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionError::Listener(e) => e.fmt(f),
+            ActionError::Failed(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for MessageError {}
+
+impl Error for ListenerError {}
+
+impl Error for ActionError {}
+
+impl From<imap::Error> for ListenerError {
+    fn from(error: imap::Error) -> ListenerError {
+        ListenerError::Connection(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::oauth2::StaleToken;
+    use crate::testing;
+
+    // Error of a command the server answered with the given status
+    fn answered(status: &str) -> imap::Error {
+        let (mut imap, _) = testing::imap_session(&format!("a2 {status} No such mailbox\r\n"));
+        imap.select("Gone").unwrap_err()
+    }
+
+    #[test]
+    fn refusals_only_concern_the_mail() {
+        for status in ["NO", "BAD"] {
+            let error = ListenerError::for_message(42, answered(status));
+            assert!(matches!(error, ListenerError::Message { uid: 42, error: MessageError::Rejected(_) }), "{error}");
+            assert!(matches!(ActionError::from(error), ActionError::Failed(_)));
+        }
+
+        let error = ListenerError::for_message(42, imap::Error::ConnectionLost);
+        assert!(matches!(error, ListenerError::Connection(imap::Error::ConnectionLost)));
+        assert!(matches!(ActionError::imap(42, imap::Error::ConnectionLost), ActionError::Listener(_)));
+    }
+
+    #[test]
+    fn sorts_transient_and_permanent_failures() {
+        let login = |error: Box<dyn Error>| ListenerError::Login(error).failure_kind();
+        assert_eq!(login(answered("NO").into()), FailureKind::Permanent);
+        assert_eq!(login(answered("BAD").into()), FailureKind::Permanent);
+        assert_eq!(login(StaleToken(answered("NO")).into()), FailureKind::Transient);
+        assert_eq!(login("token endpoint unreachable".into()), FailureKind::Transient);
+        assert_eq!(login(imap::Error::ConnectionLost.into()), FailureKind::Transient);
+
+        assert_eq!(ListenerError::Mailbox(answered("NO")).failure_kind(), FailureKind::Permanent);
+        assert_eq!(ListenerError::Mailbox(imap::Error::ConnectionLost).failure_kind(), FailureKind::Transient);
+        assert_eq!(ListenerError::Connection(imap::Error::ConnectionLost).failure_kind(), FailureKind::Transient);
+        let message = ListenerError::Message { uid: 42, error: MessageError::InvalidDate };
+        assert_eq!(message.failure_kind(), FailureKind::Transient);
+    }
+}
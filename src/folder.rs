@@ -0,0 +1,196 @@
+use base64::engine::{general_purpose, GeneralPurpose};
+use base64::{alphabet, Engine};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use log::info;
+
+const MODIFIED_BASE64: GeneralPurpose = GeneralPurpose::new(&alphabet::IMAP_MUTF7, general_purpose::NO_PAD);
+
+/// Mailbox a rule moves mail to. Written either as a plain name or as a table.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(from = "MoveTargetSpec")]
+pub struct MoveTarget {
+    // Levels separated with "/", whatever the server's hierarchy delimiter is
+    pub folder: String,
+    // Create the folder when it doesn't exist yet
+    pub create: bool,
+    // Subscribe to the folder after creating it
+    pub subscribe: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(try_from = "serde_json::Value")]
+enum MoveTargetSpec {
+    Name(String),
+    Detailed(DetailedTarget),
+}
+
+// Taken apart by hand rather than with an untagged enum, so that a misspelled setting
+// is named in the error
+impl TryFrom<serde_json::Value> for MoveTargetSpec {
+    type Error = String;
+
+    fn try_from(value: serde_json::Value) -> Result<MoveTargetSpec, String> {
+        match value {
+            serde_json::Value::String(folder) => Ok(MoveTargetSpec::Name(folder)),
+            value @ serde_json::Value::Object(_) => {
+                serde_json::from_value(value).map(MoveTargetSpec::Detailed).map_err(|e| format!("invalid folder: {e}"))
+            },
+            value => Err(format!("folder has to be a string or a table, not {value}")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DetailedTarget {
+    folder: String,
+    #[serde(default)]
+    create: bool,
+    #[serde(default)]
+    subscribe: bool,
+}
+
+impl From<MoveTargetSpec> for MoveTarget {
+    fn from(spec: MoveTargetSpec) -> MoveTarget {
+        match spec {
+            MoveTargetSpec::Name(folder) => MoveTarget { folder, create: false, subscribe: false },
+            MoveTargetSpec::Detailed(DetailedTarget { folder, create, subscribe }) => MoveTarget { folder, create, subscribe },
+        }
+    }
+}
+
+/// Encodes a mailbox name in modified UTF-7 (RFC 3501, section 5.1.3).
+pub fn encode_modified_utf7(name: &str) -> String {
+    let mut encoded = String::new();
+    let mut pending: Vec<u16> = Vec::new();
+
+    let flush = |encoded: &mut String, pending: &mut Vec<u16>| {
+        if pending.is_empty() {
+            return;
+        }
+        let bytes: Vec<u8> = pending.iter().flat_map(|unit| unit.to_be_bytes()).collect();
+        encoded.push('&');
+        encoded.push_str(&MODIFIED_BASE64.encode(bytes));
+        encoded.push('-');
+        pending.clear();
+    };
+
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush(&mut encoded, &mut pending);
+            if c == '&' {
+                encoded.push_str("&-");
+            } else {
+                encoded.push(c);
+            }
+        } else {
+            let mut units = [0u16; 2];
+            pending.extend_from_slice(c.encode_utf16(&mut units));
+        }
+    }
+    flush(&mut encoded, &mut pending);
+
+    encoded
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// LIST pattern for exactly one name. Wildcards can't be escaped in IMAP, so "*" in the
+// name becomes "%", which doesn't cross hierarchy levels; callers still have to compare
+// the returned names, as the pattern can match siblings too.
+fn list_pattern(name: &str) -> String {
+    quote(&name.replace('*', "%"))
+}
+
+/// UID COPY to the folder with the given server name. Unlike the other mailbox commands
+/// of the imap crate, its uid_copy leaves quoting the name to the caller.
+pub fn uid_copy<T: Read + Write>(
+    imap: &mut imap::Session<T>,
+    uid_set: &str,
+    server_name: &str,
+) -> imap::error::Result<()> {
+    imap.uid_copy(uid_set, quote(server_name))
+}
+
+/// Translates configured folder names to server names and makes sure they exist.
+pub struct Folders {
+    delimiter: Option<String>,
+    // Server names already known to exist on this connection
+    existing: HashSet<String>,
+}
+
+impl Folders {
+    pub fn query<T: Read + Write>(imap: &mut imap::Session<T>) -> imap::error::Result<Folders> {
+        // LIST with empty mailbox name returns just the hierarchy delimiter
+        let names = imap.list(Some(""), Some("\"\""))?;
+        let delimiter = names.iter().next().and_then(|name| name.delimiter()).map(|d| d.to_string());
+
+        Ok(Folders { delimiter, existing: HashSet::new() })
+    }
+
+    /// Name of the folder as the server knows it.
+    pub fn server_name(&self, folder: &str) -> String {
+        let delimiter = self.delimiter.as_deref().unwrap_or("/");
+        folder.split('/').map(encode_modified_utf7).collect::<Vec<_>>().join(delimiter)
+    }
+
+    /// Returns the server name of the target, creating the folder first when allowed.
+    pub fn prepare<T: Read + Write>(
+        &mut self,
+        imap: &mut imap::Session<T>,
+        target: &MoveTarget,
+    ) -> imap::error::Result<String> {
+        let server_name = self.server_name(&target.folder);
+        if !target.create || self.existing.contains(&server_name) {
+            return Ok(server_name);
+        }
+
+        let found = imap.list(Some(""), Some(&list_pattern(&server_name)))?
+            .iter()
+            .any(|name| name.name() == server_name);
+        if !found {
+            info!("Creating folder {}", target.folder);
+            imap.create(&server_name)?;
+            if target.subscribe {
+                imap.subscribe(&server_name)?;
+            }
+        }
+        self.existing.insert(server_name.clone());
+
+        Ok(server_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing;
+
+    #[test]
+    fn encodes_modified_utf7() {
+        assert_eq!(encode_modified_utf7("INBOX"), "INBOX");
+        assert_eq!(encode_modified_utf7("Entwürfe"), "Entw&APw-rfe");
+        assert_eq!(encode_modified_utf7("~peter/mail/日本語/台北"), "~peter/mail/&ZeVnLIqe-/&U,BTFw-");
+        assert_eq!(encode_modified_utf7("Tom & Jerry"), "Tom &- Jerry");
+        assert_eq!(encode_modified_utf7("Jedzenie 🍣"), "Jedzenie &2DzfYw-");
+    }
+
+    #[test]
+    fn list_pattern_keeps_wildcards_on_one_level() {
+        assert_eq!(list_pattern("Builds"), "\"Builds\"");
+        assert_eq!(list_pattern("Builds*"), "\"Builds%\"");
+        assert_eq!(list_pattern("a \"b\""), "\"a \\\"b\\\"\"");
+    }
+
+    #[test]
+    fn copies_to_quoted_names() {
+        let (mut imap, sent) = testing::imap_session("a2 OK Done\r\n");
+        uid_copy(&mut imap, "7:9", "Work/\"Q1\" reports").unwrap();
+        assert_eq!(sent.text(), "a2 UID COPY 7:9 \"Work/\\\"Q1\\\" reports\"\r\n");
+    }
+}
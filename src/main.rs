@@ -1,232 +1,305 @@
 use simple_logger::SimpleLogger;
 use structopt::StructOpt;
-use email::rfc2047::decode_rfc2047;
-use email::FromHeader;
-use std::io::{Read, Write, BufReader};
 use std::thread;
-use std::process::Command;
-use std::fs::File;
-use std::error::Error;
-use log::{info, trace, warn};
+use std::sync::Mutex;
+use log::{error, info};
+
+mod actions;
+mod address;
+mod attachments;
+mod backoff;
+mod command;
+mod config;
+mod dkim;
+mod error;
+mod folder;
+mod mime;
+mod notification;
+mod oauth2;
+mod password;
+mod paths;
+mod rules;
+mod ruleset;
+mod smtp;
+mod sound;
+mod state;
+mod status;
+mod template;
+#[cfg(test)]
+mod testing;
+mod text;
+mod verification;
+mod watchdog;
+mod webhook;
+mod worker;
+
+use config::{Auth, Config, ConfigError};
+use notification::Notifier;
+use oauth2::TokenManager;
+use ruleset::RuleSet;
+use smtp::Mailer;
+use state::StateStore;
+use status::StatusBoard;
+use webhook::WebhookQueue;
+use worker::{Credentials, Shared};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "idle")]
 struct Opt {
+    // Toml (or yaml, judging by extension) file with any of the settings below.
+    // Flags given on the command line override values from this file.
+    #[structopt(short, long)]
+    config: Option<String>,
+
     // The server name to connect to
     #[structopt(short, long)]
-    server: String,
+    server: Option<String>,
 
-    // The port to use
-    #[structopt(short, long, default_value = "993")]
-    port: u16,
+    // The port to use [default: 993]
+    #[structopt(short, long)]
+    port: Option<u16>,
 
     // The account username
     #[structopt(short, long)]
-    username: String,
+    username: Option<String>,
 
-    // The account password. In a production system passwords
-    // would normally be in a config or fetched at runtime from
-    // a password manager or user prompt and not passed on the
-    // command line.
+    // The account password. Visible to other users in `ps` output,
+    // prefer one of the other password sources. When none is given
+    // and stdin is a terminal, the password is prompted for.
     #[structopt(short = "w", long)]
-    password: String,
+    password: Option<String>,
 
-    // The mailbox to IDLE on
-    #[structopt(short, long, default_value = "INBOX")]
-    mailbox: String,
+    // Environment variable holding the account password
+    #[structopt(long)]
+    password_env: Option<String>,
 
-    // Refresh rate in seconds
-    #[structopt(short, long, default_value = "10")]
-    refresh_rate: u64,
+    // File holding the account password, has to be readable only by its owner
+    #[structopt(long)]
+    password_file: Option<String>,
 
-    // Json list of allowed people
-    #[structopt(long, default_value = "allowed_people.json")]
-    allowed_people: String,
+    // Shell command printing the account password, e.g. "pass show mail/work"
+    #[structopt(long)]
+    password_command: Option<String>,
 
-    // Json list of subjects that trigger audio
-    #[structopt(long, default_value = "triggering_subjects.json")]
-    triggering_subjects: String,
+    // The mailbox to IDLE on [default: INBOX]
+    #[structopt(short, long)]
+    mailbox: Option<String>,
 
-    // When is mail considered too old?
-    #[structopt(short = "e", long, default_value = "180")]
-    mail_expiration_secs: u32,
+    // Refresh rate in seconds [default: 10]
+    #[structopt(short, long)]
+    refresh_rate: Option<u64>,
 
-    // Audio file to play
-    #[structopt(short, long, default_value = "~/Music/kanapkiv2.wav")]
-    audio_file: String,
-}
+    // How long a single IDLE lasts before it's re-issued, at most 1740 [default: 1500]
+    #[structopt(long)]
+    idle_renew_secs: Option<u64>,
 
-fn get_subject(header: &imap::types::Fetch<'_>) -> String {
-    let envelope = header.envelope().unwrap();
+    // Reconnect when the server was silent for this long [default: 1800]
+    #[structopt(long)]
+    watchdog_secs: Option<u64>,
 
-    let _subject = envelope.subject.as_ref().unwrap();
-    let subject = String::from_utf8_lossy(_subject);
-    if let Some(decoded_subject) = decode_rfc2047(&subject) {
-        decoded_subject
-    } else {
-        subject.to_string()
-    }
-}
+    // Json list of allowed senders: display names, addresses, "*@domain" or "/regex/"
+    // [default: allowed_people.json]
+    #[structopt(long)]
+    allowed_people: Option<String>,
 
-fn get_date(header: &imap::types::Fetch<'_>) -> chrono::DateTime<chrono::Utc> {
-    let envelope = header.envelope().unwrap();
+    // Json list of subjects that trigger audio [default: triggering_subjects.json]
+    #[structopt(long)]
+    triggering_subjects: Option<String>,
 
-    let _date = envelope.date.as_ref().unwrap();
-    let date = String::from_utf8_lossy(_date);
-    let date = if let Some(decoded_date) = decode_rfc2047(&date) {
-        decoded_date
-    } else {
-        date.to_string()
-    };
-    FromHeader::from_header(date).unwrap()
-}
+    // When is mail considered too old? [default: 180]
+    #[structopt(short = "e", long)]
+    mail_expiration_secs: Option<u32>,
 
-fn get_from(header: &imap::types::Fetch<'_>) -> String {
-    let envelope = header.envelope().unwrap();
+    // Most bytes of mail text fetched for body conditions [default: 262144]
+    #[structopt(long)]
+    body_max_bytes: Option<u32>,
 
-    let _from = envelope.from.as_ref().unwrap()[0].name.as_ref().unwrap();
-    let from = String::from_utf8_lossy(_from);
-    if let Some(decoded_from) = decode_rfc2047(&from) {
-        decoded_from
-    } else {
-        from.to_string()
-    }
-}
+    // Most commands of run_command actions running at once [default: 4]
+    #[structopt(long)]
+    max_running_commands: Option<usize>,
 
-fn person_allowed(person: &String, allowed_people: &Vec<String>) -> bool {
-    allowed_people.contains(person)
-}
+    // Audio file to play, "~" and "$VARIABLES" are expanded [default: ~/Music/kanapkiv2.wav]
+    #[structopt(short, long)]
+    audio_file: Option<String>,
 
-fn parse_json_to_vector(filepath: &String) -> Result<Vec<String>, Box<dyn Error>> {
-    let file = File::open(filepath)?;
-    let reader = BufReader::new(file);
+    // Json list of rules. When not given, rules are built from
+    // allowed people and triggering subjects lists. Rule files are
+    // reloaded when they change or on SIGHUP
+    #[structopt(long)]
+    rules: Option<String>,
 
-    let vector = serde_json::from_reader(reader)?;
+    // Json file where already processed mails are remembered [default: imap_listener_state.json]
+    #[structopt(long)]
+    state_file: Option<String>,
 
-    Ok(vector)
-}
+    // Json file where workers publish their connection state [default: imap_listener_status.json]
+    #[structopt(long)]
+    status_file: Option<String>,
 
-fn subject_is_triggering(subject: &str, allowed_subjects: &Vec<String>) -> bool {
-    let subject = subject.to_lowercase();
-    allowed_subjects.iter().any(|allowed_subject| edit_distance::edit_distance(&subject, allowed_subject) <= 2)
-}
+    // Folder where mails that can't be handled are moved. When not given, they are skipped
+    #[structopt(long)]
+    quarantine_folder: Option<String>,
 
-fn mail_too_old(date: chrono::DateTime<chrono::Utc>, limit_secs: u32) -> bool {
-    let now_date = chrono::offset::Utc::now();
-    let difference_in_seconds = (now_date - date).num_seconds() as u32;
-    difference_in_seconds > limit_secs
+    #[structopt(subcommand)]
+    cmd: Option<Cmd>,
 }
 
-fn move_email<T: Read + Write>(imap: &mut imap::Session<T>, mail_uid: u32, target_folder: &str) {
-    imap.copy(mail_uid.to_string(), target_folder).unwrap();
-    imap.store(mail_uid.to_string(), "+FLAGS (\\Deleted)").unwrap();
-    imap.expunge().unwrap();
+#[derive(StructOpt, Debug)]
+enum Cmd {
+    // Parse and validate the configuration, report problems and exit without connecting
+    CheckConfig,
+    // Show connection state of workers of a running listener
+    Status,
 }
 
-fn play_notification_sound() {
-    thread::spawn(|| {
-        let opt = Opt::from_args();
-        if let Ok(mut child) = Command::new("play").arg(opt.audio_file).spawn() {
-            child.wait().expect("Command wasn't running");
-        } else {
-            warn!("Failed to run command");
+fn print_status(status_file: &str) {
+    let workers = match status::read_status(status_file) {
+        Ok(workers) => workers,
+        Err(e) => {
+            error!("Failed to read status file {status_file}: {e}");
+            std::process::exit(1);
+        },
+    };
+
+    for (name, worker) in workers {
+        let connection = if worker.connected { "connected" } else { "disconnected" };
+        println!(
+            "{name}: {connection}, circuit {:?}, {} consecutive failures, updated {}",
+            worker.circuit, worker.consecutive_failures, worker.updated
+        );
+        if let Some(last_error) = worker.last_error {
+            println!("    last error: {last_error}");
         }
-    });
+        if let Some(next_attempt) = worker.next_attempt {
+            println!("    next attempt: {next_attempt}");
+        }
+    }
 }
 
 fn main() {
-    SimpleLogger::new().init().unwrap();
+    SimpleLogger::new().with_threads(true).init().unwrap();
+
+    let opt = Opt::from_args();
+    let config = match Config::load(&opt) {
+        Ok(config) => config,
+        Err(ConfigError(problems)) => {
+            for problem in &problems {
+                error!("Configuration problem: {problem}");
+            }
+            std::process::exit(1);
+        },
+    };
 
-    'connect: loop {
-        let opt = Opt::from_args();
+    match opt.cmd {
+        Some(Cmd::CheckConfig) => {
+            info!("Configuration is valid");
+            return;
+        },
+        Some(Cmd::Status) => {
+            print_status(&config.status_file);
+            return;
+        },
+        None => {},
+    }
 
-        info!("Trying to log in to mailbox");
+    let state = match StateStore::open(&config.state_file) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("Failed to read state file {}: {e}", config.state_file);
+            std::process::exit(1);
+        },
+    };
 
-        let client = match imap::ClientBuilder::new(opt.server.clone(), opt.port).native_tls() {
-            Ok(client) => client,
-            Err(e) => {
-                let dur: std::time::Duration = std::time::Duration::from_secs(opt.refresh_rate);
-                info!("Failed to create ClientBuilder: {e:?}");
-                info!("Waiting {}s to reconnect", &opt.refresh_rate);
-                std::thread::sleep(dur);
-                continue 'connect;
-            },
+    // Credentials are resolved up front, so that password prompts don't interleave
+    let mut account_credentials = Vec::new();
+    for account in &config.accounts {
+        let credentials = match &account.auth {
+            Auth::Password(source) => source.resolve(&account.username).map(Credentials::Password),
+            Auth::OAuth2(mechanism, oauth2) => TokenManager::new(oauth2.clone())
+                .map(|tokens| Credentials::OAuth2(*mechanism, Box::new(tokens))),
         };
-
-        let mut imap: imap::Session<_> = match client.login(opt.username, opt.password) {
-            Ok(imap) => imap,
+        match credentials {
+            Ok(credentials) => account_credentials.push(Mutex::new(credentials)),
             Err(e) => {
-                let dur: std::time::Duration = std::time::Duration::from_secs(opt.refresh_rate);
-                info!("Failed to login: {e:?}");
-                info!("Waiting {}s to reconnect", &opt.refresh_rate);
-                std::thread::sleep(dur);
-                continue 'connect;
+                error!("Failed to get credentials for account \"{}\": {e}", account.name);
+                std::process::exit(1);
             },
-        };
-
-        // Turn on debug output so we can see the actual traffic coming
-        // from the server and how it is handled in our callback.
-        // This wouldn't be turned on in a production build, but is helpful
-        // in examples and for debugging.
-        imap.debug = false;
-
-        imap.select(opt.mailbox).expect("Could not select mailbox");
-
-        'fetch_mails: loop {
+        }
+    }
 
-            let search_results = match imap.search("UNSEEN") {
-                Ok(search_results) => {
-                    trace!("Search results: {:?}", &search_results);
-                    search_results
-                },
-                Err(e) => {
-                    info!("Failed to fetch emails: {e:?}");
-                    continue 'connect;
-                }
-            };
-
-            for mail_uid in search_results.iter() {
-                trace!("Parsing email of UID {mail_uid}");
-                let messages = imap.fetch(mail_uid.to_string(), "ENVELOPE").unwrap();
-                if let Some(header) = messages.iter().next() {
-                    let date = get_date(header);
-                    let from = get_from(header);
-                    let subject = get_subject(header);
-                    let allowed_people = parse_json_to_vector(&opt.allowed_people)
-                        .expect(format!("Failed to get {}", opt.allowed_people).as_ref());
-                    let triggering_subjects = parse_json_to_vector(&opt.triggering_subjects)
-                        .expect(format!("Failed to get {}", opt.triggering_subjects).as_ref());
-                    if person_allowed(&from, &allowed_people) && subject_is_triggering(&subject, &triggering_subjects) {
-                        move_email(&mut imap, *mail_uid, "Jedzenie");
-                        if !mail_too_old(date, opt.mail_expiration_secs) {
-                            trace!("New mail from {from}: \"{subject}\"");
-                            play_notification_sound();
-                        }
-                        continue 'fetch_mails; // search for mails again, because mail uid's are at this point invalid
-                    }
-                } else {
-                    warn!("Header not found :(");
-                }
-            }
+    let mailer = match config.smtp.as_ref().map(Mailer::new).transpose() {
+        Ok(mailer) => mailer,
+        Err(e) => {
+            error!("Failed to set up SMTP: {e}");
+            std::process::exit(1);
+        },
+    };
 
-            trace!("Waiting for something to arrive");
+    let rules = match RuleSet::load(&config) {
+        Ok(rules) => rules,
+        Err(e) => {
+            error!("Failed to load rules: {e}");
+            std::process::exit(1);
+        },
+    };
 
-            let dur: std::time::Duration = std::time::Duration::from_secs(opt.refresh_rate);
+    let webhooks = match WebhookQueue::open(&config.webhooks) {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            error!("Failed to read webhook queue {}: {e}", config.webhooks.queue_file);
+            std::process::exit(1);
+        },
+    };
 
-            // TODO: why this sometimes get stuck?
-            let idle_result = imap.idle().timeout(dur).wait_while(|_response| {
-                false
-            });
+    let notifier = Notifier::new(&config.notifications);
+    let status = StatusBoard::new(&config.status_file);
+    let shared = Shared {
+        config: &config,
+        rules: &rules,
+        state: &state,
+        status: &status,
+        webhooks: &webhooks,
+        notifier: &notifier,
+        mailer: mailer.as_ref(),
+    };
 
-            match idle_result {
-                Ok(reason) => trace!("IDLE finished normally {reason:?}"),
-                Err(e) => {
-                    info!("IDLE finished with error: {e:?}");
-                    continue 'connect;
+    thread::scope(|scope| {
+        let (rules, config) = (&rules, &config);
+        thread::Builder::new()
+            .name("rules-watcher".to_string())
+            .spawn_scoped(scope, move || {
+                if let Err(e) = ruleset::watch_files(rules, config) {
+                    error!("Stopped watching rule files, send SIGHUP to reload rules: {e}");
+                }
+            })
+            .expect("Failed to spawn rules watcher");
+        thread::Builder::new()
+            .name("rules-hangup".to_string())
+            .spawn_scoped(scope, move || {
+                if let Err(e) = ruleset::reload_on_hangup(rules, config) {
+                    error!("Failed to listen for SIGHUP: {e}");
                 }
+            })
+            .expect("Failed to spawn SIGHUP listener");
+        let webhooks = &webhooks;
+        thread::Builder::new()
+            .name("webhook-retry".to_string())
+            .spawn_scoped(scope, move || webhooks.retry_forever())
+            .expect("Failed to spawn webhook retry thread");
+        let (notifier, account_credentials) = (&notifier, &account_credentials);
+        thread::Builder::new()
+            .name("notification-buttons".to_string())
+            .spawn_scoped(scope, move || notifier.handle_buttons(config, account_credentials))
+            .expect("Failed to spawn notification button handler");
+
+        let shared = &shared;
+        for (account, credentials) in config.accounts.iter().zip(account_credentials) {
+            for mailbox in &account.mailboxes {
+                thread::Builder::new()
+                    .name(format!("{}/{}", account.name, mailbox))
+                    .spawn_scoped(scope, move || worker::watch_mailbox(shared, account, credentials, mailbox))
+                    .expect("Failed to spawn mailbox worker");
             }
-
         }
-        //imap.logout().expect("Could not log out");
-    }
+    });
 }
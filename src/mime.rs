@@ -0,0 +1,438 @@
+use base64::Engine;
+use encoding::label::encoding_from_whatwg_label;
+use encoding::DecoderTrap;
+use email::rfc2047::decode_rfc2047;
+use imap_proto::types::{BodyContentCommon, BodyParams, BodyStructure, ContentEncoding};
+
+/// A text part of a mail, as described by its BODYSTRUCTURE.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextPart {
+    // Part number, e.g. [1, 2] for section "1.2"
+    pub path: Vec<u32>,
+    pub html: bool,
+    pub encoding: TransferEncoding,
+    pub charset: Option<String>,
+    pub octets: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferEncoding {
+    // 7bit, 8bit, binary and anything unknown
+    Identity,
+    Base64,
+    QuotedPrintable,
+}
+
+/// A part that comes as a file rather than as text of the mail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub path: Vec<u32>,
+    // Lowercase "type/subtype"
+    pub mime_type: String,
+    pub filename: Option<String>,
+    pub encoding: TransferEncoding,
+    // Size as sent, before transfer decoding
+    pub octets: u32,
+}
+
+// Section name for BODY[...], e.g. "1.2"
+fn section_name(path: &[u32]) -> String {
+    path.iter().map(u32::to_string).collect::<Vec<_>>().join(".")
+}
+
+impl TextPart {
+    /// Section name for BODY[...].
+    pub fn section(&self) -> String {
+        section_name(&self.path)
+    }
+}
+
+impl Attachment {
+    /// Section name for BODY[...].
+    pub fn section(&self) -> String {
+        section_name(&self.path)
+    }
+
+    /// Approximate size once decoded.
+    pub fn size(&self) -> u32 {
+        match self.encoding {
+            TransferEncoding::Base64 => self.octets / 4 * 3,
+            _ => self.octets,
+        }
+    }
+}
+
+fn param<'a>(params: &'a BodyParams<'_>, name: &str) -> Option<&'a str> {
+    params.as_ref()?.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_ref())
+}
+
+fn transfer_encoding(encoding: &ContentEncoding<'_>) -> TransferEncoding {
+    match encoding {
+        ContentEncoding::Base64 => TransferEncoding::Base64,
+        ContentEncoding::QuotedPrintable => TransferEncoding::QuotedPrintable,
+        _ => TransferEncoding::Identity,
+    }
+}
+
+// RFC 2231 "charset'language'percent%20encoded" value
+fn decode_extended_value(value: &str) -> Option<String> {
+    let mut fields = value.splitn(3, '\'');
+    let (charset, _language, encoded) = (fields.next()?, fields.next()?, fields.next()?);
+
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (byte, hex) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            },
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            },
+        }
+    }
+
+    match encoding_from_whatwg_label(charset) {
+        Some(encoding) => encoding.decode(&bytes, DecoderTrap::Replace).ok(),
+        None => Some(String::from_utf8_lossy(&bytes).to_string()),
+    }
+}
+
+// File name from a "filename" or "name" parameter, plain, RFC 2047 or RFC 2231 encoded
+fn filename_param(params: &BodyParams<'_>, name: &str) -> Option<String> {
+    if let Some(value) = param(params, &format!("{name}*")).and_then(decode_extended_value) {
+        return Some(value);
+    }
+    let value = param(params, name)?;
+    Some(decode_rfc2047(value).unwrap_or_else(|| value.to_string()))
+}
+
+fn filename(common: &BodyContentCommon<'_>) -> Option<String> {
+    common.disposition.as_ref()
+        .and_then(|disposition| filename_param(&disposition.params, "filename"))
+        .or_else(|| filename_param(&common.ty.params, "name"))
+}
+
+// Parts marked as attachments or carrying a file name are files, not text of the mail
+fn is_attachment(common: &BodyContentCommon<'_>) -> bool {
+    common.disposition.as_ref().is_some_and(|d| d.ty.eq_ignore_ascii_case("attachment")) || filename(common).is_some()
+}
+
+fn collect(structure: &BodyStructure<'_>, path: Vec<u32>, parts: &mut Vec<TextPart>) {
+    match structure {
+        BodyStructure::Text { common, other, .. } => {
+            let html = common.ty.subtype.eq_ignore_ascii_case("html");
+            if is_attachment(common) || !(html || common.ty.subtype.eq_ignore_ascii_case("plain")) {
+                return;
+            }
+            parts.push(TextPart {
+                path,
+                html,
+                encoding: transfer_encoding(&other.transfer_encoding),
+                charset: param(&common.ty.params, "charset").map(str::to_string),
+                octets: other.octets,
+            });
+        },
+        BodyStructure::Multipart { common, bodies, .. } => {
+            let children = bodies.iter().enumerate().map(|(index, body)| {
+                let mut child_path = path.clone();
+                child_path.push(index as u32 + 1);
+                let mut child_parts = Vec::new();
+                collect(body, child_path, &mut child_parts);
+                child_parts
+            });
+
+            if common.ty.subtype.eq_ignore_ascii_case("alternative") {
+                // Same content in several forms, plain text is the easiest to match on
+                let alternatives: Vec<Vec<TextPart>> = children.filter(|c| !c.is_empty()).collect();
+                let chosen = alternatives.iter().position(|c| c.iter().all(|part| !part.html)).unwrap_or(0);
+                parts.extend(alternatives.into_iter().nth(chosen).unwrap_or_default());
+            } else {
+                children.for_each(|child_parts| parts.extend(child_parts));
+            }
+        },
+        // Attachments and forwarded mails aren't part of the text
+        BodyStructure::Basic { .. } | BodyStructure::Message { .. } => {},
+    }
+}
+
+/// Text parts worth matching on: inline plain text, or HTML where there's no plain
+/// alternative, in order of appearance.
+pub fn text_parts(structure: &BodyStructure<'_>) -> Vec<TextPart> {
+    let mut parts = Vec::new();
+    match structure {
+        // Parts of a multipart mail are numbered from 1, a single part mail is part 1
+        BodyStructure::Multipart { .. } => collect(structure, Vec::new(), &mut parts),
+        _ => collect(structure, vec![1], &mut parts),
+    }
+    parts
+}
+
+fn collect_attachments(structure: &BodyStructure<'_>, path: Vec<u32>, attachments: &mut Vec<Attachment>) {
+    let (common, other) = match structure {
+        BodyStructure::Multipart { bodies, .. } => {
+            for (index, body) in bodies.iter().enumerate() {
+                let mut child_path = path.clone();
+                child_path.push(index as u32 + 1);
+                collect_attachments(body, child_path, attachments);
+            }
+            return;
+        },
+        BodyStructure::Basic { common, other, .. }
+        | BodyStructure::Text { common, other, .. }
+        | BodyStructure::Message { common, other, .. } => (common, other),
+    };
+
+    if is_attachment(common) {
+        attachments.push(Attachment {
+            path,
+            mime_type: format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase(),
+            filename: filename(common),
+            encoding: transfer_encoding(&other.transfer_encoding),
+            octets: other.octets,
+        });
+    }
+}
+
+/// Every attachment of a mail, in order of appearance. Files attached to forwarded
+/// mails are not included, the forwarded mail itself is when it's attached.
+pub fn attachments(structure: &BodyStructure<'_>) -> Vec<Attachment> {
+    let mut attachments = Vec::new();
+    match structure {
+        BodyStructure::Multipart { .. } => collect_attachments(structure, Vec::new(), &mut attachments),
+        _ => collect_attachments(structure, vec![1], &mut attachments),
+    }
+    attachments
+}
+
+/// Undoes the transfer encoding of a part.
+pub fn decode_transfer(encoding: TransferEncoding, data: &[u8]) -> Vec<u8> {
+    match encoding {
+        TransferEncoding::Identity => data.to_vec(),
+        TransferEncoding::Base64 => decode_base64(data),
+        TransferEncoding::QuotedPrintable => decode_quoted_printable(data),
+    }
+}
+
+fn decode_quoted_printable(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut index = 0;
+    while index < data.len() {
+        if data[index] != b'=' {
+            decoded.push(data[index]);
+            index += 1;
+            continue;
+        }
+        let rest = &data[index + 1..];
+        if rest.starts_with(b"\r\n") {
+            index += 3; // soft line break
+        } else if rest.starts_with(b"\n") {
+            index += 2;
+        } else if let Some(byte) = rest.get(..2).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()) {
+            decoded.push(byte);
+            index += 3;
+        } else {
+            // Broken or cut off by the size cap, keep as is
+            decoded.push(b'=');
+            index += 1;
+        }
+    }
+    decoded
+}
+
+fn decode_base64(data: &[u8]) -> Vec<u8> {
+    let mut cleaned: Vec<u8> = data.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect();
+    // The size cap may cut a quantum in half
+    if !cleaned.ends_with(b"=") {
+        cleaned.truncate(cleaned.len() - cleaned.len() % 4);
+    }
+    base64::engine::general_purpose::STANDARD.decode(cleaned).unwrap_or_default()
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code = entity.strip_prefix('#')?;
+            let code = match code.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            char::from_u32(code)
+        },
+    }
+}
+
+/// Rough text rendering of HTML: tags become whitespace, scripts and styles are dropped
+/// and entities decoded.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find(['<', '&']) {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with('&') {
+            let entity = rest[1..].find(';').filter(|&end| end <= 10).and_then(|end| {
+                decode_entity(&rest[1..1 + end]).map(|c| (c, end + 2))
+            });
+            match entity {
+                Some((c, length)) => {
+                    text.push(c);
+                    rest = &rest[length..];
+                },
+                None => {
+                    text.push('&');
+                    rest = &rest[1..];
+                },
+            }
+            continue;
+        }
+
+        let tag_end = rest.find('>').map_or(rest.len(), |end| end + 1);
+        let tag = rest[1..tag_end].trim_start_matches('/').to_ascii_lowercase();
+        let name: String = tag.chars().take_while(|c| c.is_ascii_alphanumeric()).collect();
+        rest = &rest[tag_end..];
+
+        if name == "script" || name == "style" {
+            let closing = format!("</{name}");
+            let end = rest.to_ascii_lowercase().find(&closing).unwrap_or(rest.len());
+            rest = &rest[end..];
+            rest = &rest[rest.find('>').map_or(rest.len(), |end| end + 1)..];
+        }
+        let block = ["br", "p", "div", "tr", "li", "h1", "h2", "h3", "h4", "h5", "h6", "table", "blockquote"];
+        text.push(if block.contains(&name.as_str()) { '\n' } else { ' ' });
+    }
+    text.push_str(rest);
+
+    text.lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>().join("\n")
+}
+
+/// Turns the fetched bytes of a part into text.
+pub fn decode_part(part: &TextPart, data: &[u8]) -> String {
+    let data = decode_transfer(part.encoding, data);
+
+    let encoding = part.charset.as_deref().and_then(encoding_from_whatwg_label);
+    let text = match encoding {
+        Some(encoding) => encoding.decode(&data, DecoderTrap::Replace).unwrap_or_default(),
+        None => String::from_utf8_lossy(&data).to_string(),
+    };
+
+    if part.html {
+        html_to_text(&text)
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap_proto::types::{AttributeValue, Response};
+
+    // Runs the structure through the FETCH parser, as it comes from the server
+    fn with_structure<T>(structure: &str, check: impl FnOnce(&BodyStructure<'_>) -> T) -> T {
+        let response = format!("* 1 FETCH (BODYSTRUCTURE {structure})\r\n");
+        let (_, parsed) = imap_proto::parser::parse_response(response.as_bytes()).unwrap();
+        let Response::Fetch(_, attributes) = parsed else { panic!("not a FETCH response") };
+        let structure = attributes.iter().find_map(|attribute| match attribute {
+            AttributeValue::BodyStructure(structure) => Some(structure),
+            _ => None,
+        });
+        check(structure.unwrap())
+    }
+
+    const MIXED: &str = r#"((("TEXT" "PLAIN" ("CHARSET" "UTF-8") NIL NIL "QUOTED-PRINTABLE" 120 4 NIL NIL NIL)("TEXT" "HTML" ("CHARSET" "UTF-8") NIL NIL "BASE64" 300 5 NIL NIL NIL) "ALTERNATIVE" ("BOUNDARY" "b2") NIL NIL)("TEXT" "PLAIN" ("CHARSET" "US-ASCII") NIL NIL "7BIT" 40 2 NIL ("ATTACHMENT" ("FILENAME" "notes.txt")) NIL)("APPLICATION" "PDF" ("NAME" "=?UTF-8?B?bWVudSDFm3JvZGEucGRm?=") NIL NIL "BASE64" 4000 NIL ("ATTACHMENT" NIL) NIL) "MIXED" ("BOUNDARY" "b1") NIL NIL)"#;
+
+    #[test]
+    fn picks_plain_alternative_and_skips_attachments() {
+        let parts = with_structure(MIXED, text_parts);
+        assert_eq!(parts, [TextPart {
+            path: vec![1, 1],
+            html: false,
+            encoding: TransferEncoding::QuotedPrintable,
+            charset: Some("UTF-8".to_string()),
+            octets: 120,
+        }]);
+        assert_eq!(parts[0].section(), "1.1");
+    }
+
+    #[test]
+    fn falls_back_to_html_alternative() {
+        let structure = r#"(("TEXT" "HTML" ("CHARSET" "UTF-8") NIL NIL "BASE64" 300 5 NIL NIL NIL) "ALTERNATIVE" ("BOUNDARY" "b") NIL NIL)"#;
+        let parts = with_structure(structure, text_parts);
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].html);
+        assert_eq!(parts[0].path, [1]);
+    }
+
+    #[test]
+    fn single_part_mail_is_part_one() {
+        let structure = r#"("TEXT" "HTML" ("CHARSET" "iso-8859-2") NIL NIL "7BIT" 50 2)"#;
+        let parts = with_structure(structure, text_parts);
+        assert_eq!(parts[0].section(), "1");
+        assert_eq!(parts[0].charset.as_deref(), Some("iso-8859-2"));
+    }
+
+    #[test]
+    fn lists_attachments_with_decoded_names() {
+        let attachments = with_structure(MIXED, attachments);
+        let names: Vec<_> = attachments.iter().map(|a| (a.section(), a.mime_type.as_str(), a.filename.as_deref())).collect();
+        assert_eq!(names, [
+            ("2".to_string(), "text/plain", Some("notes.txt")),
+            ("3".to_string(), "application/pdf", Some("menu środa.pdf")),
+        ]);
+        assert_eq!(attachments[1].size(), 3000);
+    }
+
+    #[test]
+    fn decodes_rfc2231_names() {
+        assert_eq!(decode_extended_value("utf-8''%C5%81%C3%B3d%C5%BA.pdf").as_deref(), Some("Łódź.pdf"));
+        assert_eq!(decode_extended_value("iso-8859-2'pl'%B3%F3d%BC").as_deref(), Some("łódź"));
+    }
+
+    #[test]
+    fn decodes_quoted_printable() {
+        assert_eq!(decode_quoted_printable(b"Zam=C3=B3wienie=\r\n gotowe =3D ok"), "Zamówienie gotowe = ok".as_bytes());
+        assert_eq!(decode_quoted_printable(b"soft=\nbreak"), b"softbreak");
+        // Cut off by the size cap or just broken
+        assert_eq!(decode_quoted_printable(b"a=ZZb=C"), b"a=ZZb=C");
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64(b"U3Vz\r\naGk="), b"Sushi");
+        // A quantum cut in half by the size cap is dropped
+        assert_eq!(decode_base64(b"U3VzaGkg\r\ndG9k"), b"Sushi tod");
+        assert_eq!(decode_base64(b"U3VzaGkgdG9"), b"Sushi ");
+        assert_eq!(decode_transfer(TransferEncoding::Identity, b"as is"), b"as is");
+    }
+
+    #[test]
+    fn renders_html_as_text() {
+        let html = "<html><head><style>p { color: red }</style><script>alert('<p>')</script></head>\
+            <body><p>Menu &amp; prices</p><div>Sushi&nbsp;&lt;3 &#x142;&#243;d&#378;</div>AT&T<br>end</body></html>";
+        assert_eq!(html_to_text(html), "Menu & prices\nSushi <3 łódź\nAT&T\nend");
+    }
+
+    #[test]
+    fn decodes_part_charset() {
+        let part = TextPart {
+            path: vec![1],
+            html: false,
+            encoding: TransferEncoding::QuotedPrintable,
+            charset: Some("iso-8859-2".to_string()),
+            octets: 0,
+        };
+        assert_eq!(decode_part(&part, b"=B3=F3d=BC"), "łódź");
+    }
+}
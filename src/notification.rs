@@ -0,0 +1,473 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::Duration;
+use log::{info, warn};
+
+use crate::actions::{Action, ActionContext, Outcome};
+use crate::attachments::safe_filename;
+use crate::command;
+use crate::config::{Account, Config};
+use crate::error::ActionError;
+use crate::paths;
+use crate::template::Template;
+use crate::watchdog::Watchdog;
+use crate::worker::{self, Credentials};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct NotificationConfig {
+    // Program the Open button hands the mail to, as an .eml file
+    pub open_command: String,
+    // How long notifications are shown; left to the notification server when not set
+    pub timeout_secs: Option<u32>,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> NotificationConfig {
+        NotificationConfig {
+            open_command: "xdg-open".to_string(),
+            timeout_secs: None,
+        }
+    }
+}
+
+impl NotificationConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.open_command.is_empty() {
+            problems.push("notifications.open_command can't be empty".to_string());
+        }
+        problems
+    }
+}
+
+/// Buttons a notification can offer.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Button {
+    // Fetch the mail and open it with notifications.open_command
+    Open,
+    MarkRead,
+}
+
+impl Button {
+    const ALL: [Button; 2] = [Button::Open, Button::MarkRead];
+
+    // Identifies the button in D-Bus messages
+    fn key(self) -> &'static str {
+        match self {
+            Button::Open => "open",
+            Button::MarkRead => "mark_read",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Button::Open => "Open",
+            Button::MarkRead => "Mark read",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<Button> {
+        Button::ALL.into_iter().find(|button| button.key() == key)
+    }
+}
+
+/// Settings of the notify action.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Notify {
+    // "New mail from <senders>" when not given
+    pub summary: Option<Template>,
+    // Subject of the mail when not given
+    pub body: Option<Template>,
+    #[serde(default = "default_buttons")]
+    pub buttons: Vec<Button>,
+}
+
+fn default_buttons() -> Vec<Button> {
+    Button::ALL.to_vec()
+}
+
+// Placeholder value as text in a notification body with markup
+fn escape_markup(value: String) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+impl Action for Notify {
+    fn name(&self) -> &'static str {
+        "notify"
+    }
+
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        let values = context.template_context();
+        let summary = match &self.summary {
+            Some(summary) => summary.render(&values, |value| value),
+            None => format!("New mail from {}", context.message.senders_text()),
+        };
+        let escape: fn(String) -> String = if context.notifier.markup() { escape_markup } else { |value| value };
+        let body = match &self.body {
+            Some(body) => body.render(&values, escape),
+            None => escape(context.message.subject.clone()),
+        };
+
+        let mail = NotifiedMail {
+            account: context.account.name.clone(),
+            mailbox: context.mailbox.to_string(),
+            uid: context.message.uid,
+            uid_validity: context.session.uid_validity,
+        };
+        context.notifier.show(&summary, &body, &self.buttons, mail);
+        Ok(Outcome::Kept)
+    }
+}
+
+// Mail a notification with buttons is about
+#[derive(Debug, Clone)]
+struct NotifiedMail {
+    account: String,
+    mailbox: String,
+    uid: u32,
+    // Mailbox it was in when notified, a different one now means the UID is stale
+    uid_validity: u32,
+}
+
+/// Shows notifications on the desktop, or writes them to the log when that can't be done.
+/// Shared by all workers.
+pub struct Notifier {
+    config: NotificationConfig,
+    // None when no session bus is running
+    bus: Option<bus::Bus>,
+    // Mails of notifications whose buttons can still be pressed, by notification id
+    shown: Mutex<HashMap<u32, NotifiedMail>>,
+}
+
+impl Notifier {
+    pub fn new(config: &NotificationConfig) -> Notifier {
+        let bus = match bus::Bus::connect() {
+            Ok(bus) => Some(bus),
+            Err(e) => {
+                info!("No desktop notifications, they are logged instead: {e}");
+                None
+            },
+        };
+
+        Notifier {
+            config: config.clone(),
+            bus,
+            shown: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether bodies are shown with markup, so that "<", ">" and "&" in them need escaping.
+    pub fn markup(&self) -> bool {
+        self.bus.as_ref().is_some_and(bus::Bus::markup)
+    }
+
+    fn show(&self, summary: &str, body: &str, buttons: &[Button], mail: NotifiedMail) {
+        if let Some(bus) = &self.bus {
+            let actions: Vec<&str> = buttons.iter().flat_map(|button| [button.key(), button.label()]).collect();
+            let timeout = self.config.timeout_secs.map_or(-1, |secs| secs.saturating_mul(1000).min(i32::MAX as u32) as i32);
+            match bus.notify(summary, body, &actions, timeout) {
+                Ok(id) => {
+                    if !buttons.is_empty() {
+                        self.shown.lock().unwrap().insert(id, mail);
+                    }
+                    return;
+                },
+                Err(e) => warn!("Failed to show desktop notification: {e}"),
+            }
+        }
+        info!("{summary}: {body}");
+    }
+
+    /// Carries out button presses as long as the session bus is there; returns right away
+    /// without one.
+    pub fn handle_buttons(&self, config: &Config, credentials: &[Mutex<Credentials>]) {
+        let Some(bus) = &self.bus else { return };
+        let signals = match bus.signals() {
+            Ok(signals) => signals,
+            Err(e) => {
+                warn!("Failed to listen for notification buttons: {e}");
+                return;
+            },
+        };
+        let watchdog = Watchdog::spawn(Duration::from_secs(config.watchdog_secs));
+
+        for signal in signals {
+            match signal {
+                Signal::Invoked(id, key) => {
+                    // Clicks on the notification itself come as "default"
+                    let Some(button) = Button::from_key(&key) else { continue };
+                    let Some(mail) = self.shown.lock().unwrap().remove(&id) else { continue };
+                    let Some((account, credentials)) = config.accounts.iter().zip(credentials)
+                        .find(|(account, _)| account.name == mail.account) else { continue };
+
+                    if let Err(e) = self.press(button, &mail, account, credentials, &watchdog) {
+                        warn!("{} button on mail of UID {} in {}/{} failed: {e}",
+                            button.label(), mail.uid, mail.account, mail.mailbox);
+                    }
+                    watchdog.disarm();
+                },
+                Signal::Closed(id) => {
+                    self.shown.lock().unwrap().remove(&id);
+                },
+            }
+        }
+        warn!("Session bus went away, notification buttons do nothing anymore");
+    }
+
+    // Does what the button says over a connection of its own, as the worker's may be idling
+    fn press(
+        &self,
+        button: Button,
+        mail: &NotifiedMail,
+        account: &Account,
+        credentials: &Mutex<Credentials>,
+        watchdog: &Watchdog,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut session = worker::open_session(account, credentials, &mail.mailbox, watchdog)?;
+        let result = if session.uid_validity != mail.uid_validity {
+            Err("mailbox was recreated, the mail is gone".into())
+        } else {
+            match button {
+                Button::Open => self.open(&mut session, mail),
+                Button::MarkRead => session.imap.uid_store(mail.uid.to_string(), "+FLAGS.SILENT (\\Seen)")
+                    .map(drop).map_err(Into::into),
+            }
+        };
+        let _ = session.imap.logout();
+        result
+    }
+
+    fn open(&self, session: &mut worker::Session, mail: &NotifiedMail) -> Result<(), Box<dyn Error>> {
+        let raw = worker::fetch_raw(session, mail.uid)?;
+        let path = private_directory()?
+            .join(safe_filename(&format!("{}-{}-{}.eml", mail.account, mail.mailbox, mail.uid).replace('/', "_")));
+        paths::write_private(&path, &raw)?;
+
+        let mut command = Command::new(&self.config.open_command);
+        command.arg(&path);
+        command::spawn_background(command)?;
+        Ok(())
+    }
+}
+
+// Directory for mails handed to open_command that no other user can look into or
+// put files in: the per-user runtime directory, or one of our own in the temp directory
+fn private_directory() -> io::Result<PathBuf> {
+    let directory = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) if !runtime.is_empty() => PathBuf::from(runtime).join("imap-listener"),
+        _ => std::env::temp_dir().join(format!("imap-listener-{}", user_id())),
+    };
+    make_private_directory(&directory)?;
+    Ok(directory)
+}
+
+fn make_private_directory(directory: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    match builder.create(directory) {
+        Ok(()) => {},
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {},
+        Err(e) => return Err(e),
+    }
+
+    // Somebody else may have made it first in a shared temp directory
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let metadata = fs::symlink_metadata(directory)?;
+        if !metadata.is_dir() || metadata.uid() != user_id() || metadata.permissions().mode() & 0o077 != 0 {
+            return Err(io::Error::other(format!("{} isn't a directory only we can use", directory.display())));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn user_id() -> u32 {
+    // SAFETY: getuid can't fail and has no side effects
+    unsafe { libc::getuid() }
+}
+
+#[cfg(not(unix))]
+fn user_id() -> u32 {
+    0
+}
+
+// Events of the notification service
+#[cfg_attr(not(feature = "desktop-notifications"), allow(dead_code))]
+enum Signal {
+    // Button of given key pressed on the notification of given id
+    Invoked(u32, String),
+    Closed(u32),
+}
+
+#[cfg(feature = "desktop-notifications")]
+mod bus {
+    use std::collections::HashMap;
+    use zbus::blocking::{Connection, Proxy};
+    use zbus::zvariant::Value;
+
+    use super::Signal;
+
+    /// Connection to the org.freedesktop.Notifications service.
+    pub struct Bus {
+        proxy: Proxy<'static>,
+        markup: bool,
+    }
+
+    impl Bus {
+        pub fn connect() -> zbus::Result<Bus> {
+            let connection = Connection::session()?;
+            let proxy = Proxy::new(
+                &connection,
+                "org.freedesktop.Notifications",
+                "/org/freedesktop/Notifications",
+                "org.freedesktop.Notifications",
+            )?;
+            // Notification server may only be started by the first notification
+            let capabilities: Vec<String> = proxy.call("GetCapabilities", &()).unwrap_or_default();
+            Ok(Bus { markup: capabilities.iter().any(|capability| capability == "body-markup"), proxy })
+        }
+
+        pub fn markup(&self) -> bool {
+            self.markup
+        }
+
+        /// Shows a notification, returns its id.
+        pub fn notify(&self, summary: &str, body: &str, actions: &[&str], timeout_ms: i32) -> zbus::Result<u32> {
+            let hints = HashMap::from([("category", Value::from("email.arrived"))]);
+            self.proxy.call("Notify", &("imap-listener", 0u32, "mail-unread", summary, body, actions, hints, timeout_ms))
+        }
+
+        pub fn signals(&self) -> zbus::Result<impl Iterator<Item = Signal>> {
+            Ok(self.proxy.receive_all_signals()?.filter_map(|message| {
+                let body = message.body();
+                match message.header().member()?.as_str() {
+                    "ActionInvoked" => body.deserialize().ok().map(|(id, key)| Signal::Invoked(id, key)),
+                    "NotificationClosed" => body.deserialize().ok().map(|(id, _reason): (u32, u32)| Signal::Closed(id)),
+                    _ => None,
+                }
+            }))
+        }
+    }
+}
+
+// Never connects, so that everything is logged
+#[cfg(not(feature = "desktop-notifications"))]
+mod bus {
+    use super::Signal;
+
+    pub enum Bus {}
+
+    impl Bus {
+        pub fn connect() -> Result<Bus, &'static str> {
+            Err("built without the desktop-notifications feature")
+        }
+
+        pub fn markup(&self) -> bool {
+            match *self {}
+        }
+
+        pub fn notify(&self, _summary: &str, _body: &str, _actions: &[&str], _timeout_ms: i32) -> Result<u32, &'static str> {
+            match *self {}
+        }
+
+        pub fn signals(&self) -> Result<std::iter::Empty<Signal>, &'static str> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fresh directory under the temp directory, removed when dropped
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Scratch {
+            let path = std::env::temp_dir().join(format!("imap-listener-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir(&path).unwrap();
+            Scratch(path)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        fs::symlink_metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn opened_mails_are_private() {
+        let scratch = Scratch::new("open");
+        let directory = scratch.0.join("mails");
+        make_private_directory(&directory).unwrap();
+        // Using it again is fine
+        make_private_directory(&directory).unwrap();
+
+        let path = directory.join("mail.eml");
+        paths::write_private(&path, b"first").unwrap();
+        paths::write_private(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        #[cfg(unix)]
+        {
+            assert_eq!(mode(&directory), 0o700);
+            assert_eq!(mode(&path), 0o600);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_directories_others_can_use() {
+        use std::os::unix::fs::PermissionsExt;
+        let scratch = Scratch::new("open-shared");
+
+        let shared = scratch.0.join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(make_private_directory(&shared).is_err());
+
+        let link = scratch.0.join("link");
+        std::os::unix::fs::symlink(&shared, &link).unwrap();
+        assert!(make_private_directory(&link).is_err());
+    }
+
+    #[test]
+    fn escapes_markup() {
+        assert_eq!(escape_markup("<b>Tom & Jerry</b>".to_string()), "&lt;b&gt;Tom &amp; Jerry&lt;/b&gt;");
+    }
+
+    #[test]
+    fn buttons_round_trip_through_keys() {
+        for button in Button::ALL {
+            assert_eq!(Button::from_key(button.key()), Some(button));
+        }
+        assert_eq!(Button::from_key("default"), None);
+        let notify: Notify = serde_json::from_str(r#"{"buttons": ["mark_read"]}"#).unwrap();
+        assert_eq!(notify.buttons, [Button::MarkRead]);
+        let notify: Notify = serde_json::from_str("{}").unwrap();
+        assert_eq!(notify.buttons, Button::ALL);
+    }
+}
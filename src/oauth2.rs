@@ -0,0 +1,293 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use log::{info, trace};
+
+use crate::paths;
+
+// Access tokens are renewed this long before they expire
+const EXPIRY_MARGIN_SECS: i64 = 300;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mechanism {
+    Xoauth2,
+    Oauthbearer,
+}
+
+impl Mechanism {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mechanism::Xoauth2 => "XOAUTH2",
+            Mechanism::Oauthbearer => "OAUTHBEARER",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OAuth2Config {
+    // Token endpoint, e.g. https://oauth2.googleapis.com/token
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scope: Option<String>,
+    // Initial refresh token, only used until token_file has one
+    pub refresh_token: Option<String>,
+    // Where current access and refresh tokens are kept between runs
+    pub token_file: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StoredToken {
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+    // Unix timestamp
+    expires_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+}
+
+/// Hands out access tokens, refreshing them when they are about to expire.
+pub struct TokenManager {
+    config: OAuth2Config,
+    token: StoredToken,
+    agent: ureq::Agent,
+}
+
+impl TokenManager {
+    pub fn new(config: OAuth2Config) -> Result<TokenManager, Box<dyn Error>> {
+        let mut token: StoredToken = match fs::read_to_string(&config.token_file) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StoredToken::default(),
+            Err(e) => return Err(e.into()),
+        };
+        if token.refresh_token.is_none() {
+            token.refresh_token = config.refresh_token.clone();
+        }
+
+        let agent = ureq::AgentBuilder::new()
+            .tls_connector(Arc::new(native_tls::TlsConnector::new()?))
+            .build();
+
+        Ok(TokenManager { config, token, agent })
+    }
+
+    /// Returns a valid access token, refreshing it first if needed.
+    pub fn access_token(&mut self) -> Result<String, Box<dyn Error>> {
+        match self.cached() {
+            Some(access_token) => Ok(access_token),
+            None => self.refresh(),
+        }
+    }
+
+    /// Stored access token, as long as it isn't about to expire.
+    pub fn cached(&self) -> Option<String> {
+        let now = chrono::offset::Utc::now().timestamp();
+        match (&self.token.access_token, self.token.expires_at) {
+            (Some(access_token), Some(expires_at)) if expires_at - EXPIRY_MARGIN_SECS > now => Some(access_token.clone()),
+            (Some(access_token), None) => Some(access_token.clone()),
+            _ => None,
+        }
+    }
+
+    /// Forgets the current access token, e.g. after the server rejected it.
+    pub fn invalidate(&mut self) {
+        self.token.access_token = None;
+    }
+
+    fn refresh(&mut self) -> Result<String, Box<dyn Error>> {
+        let refresh_token = self.token.refresh_token.clone()
+            .ok_or("no refresh token available, set oauth2.refresh_token")?;
+
+        info!("Refreshing OAuth2 access token");
+
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+        ];
+        if let Some(client_secret) = &self.config.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        if let Some(scope) = &self.config.scope {
+            form.push(("scope", scope));
+        }
+
+        let response: TokenResponse = self.agent.post(&self.config.token_url).send_form(&form)?.into_json()?;
+
+        let now = chrono::offset::Utc::now().timestamp();
+        self.token.access_token = Some(response.access_token.clone());
+        self.token.expires_at = response.expires_in.map(|expires_in| now + expires_in);
+        if response.refresh_token.is_some() {
+            self.token.refresh_token = response.refresh_token;
+        }
+        self.save()?;
+        trace!("OAuth2 access token valid until {:?}", self.token.expires_at);
+
+        Ok(response.access_token)
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        // Written aside and renamed, so that a crash can't lose the refresh token
+        paths::write_private(Path::new(&self.config.token_file), serde_json::to_string_pretty(&self.token)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Server rejected an access token that was stored rather than just refreshed. It may
+/// have been revoked or expired early, so logging in again with a fresh one may work.
+#[derive(Debug)]
+pub struct StaleToken(pub imap::Error);
+
+impl fmt::Display for StaleToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stored access token rejected: {}", self.0)
+    }
+}
+
+impl Error for StaleToken {}
+
+/// SASL response for XOAUTH2 or OAUTHBEARER.
+pub struct OAuth2Authenticator {
+    pub mechanism: Mechanism,
+    pub user: String,
+    pub access_token: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl imap::Authenticator for OAuth2Authenticator {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> Self::Response {
+        // A non-empty challenge carries the server's error details, and
+        // the exchange has to be finished with an empty response
+        if !challenge.is_empty() {
+            trace!("{} failed: {}", self.mechanism.name(), String::from_utf8_lossy(challenge));
+            return String::new();
+        }
+
+        match self.mechanism {
+            Mechanism::Xoauth2 => format!("user={}\x01auth=Bearer {}\x01\x01", self.user, self.access_token),
+            Mechanism::Oauthbearer => format!(
+                "n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+                self.user, self.host, self.port, self.access_token
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imap::Authenticator;
+
+    use crate::testing::{self, TempFile};
+
+    fn manager(token_file: &TempFile, token_url: &str) -> TokenManager {
+        TokenManager::new(OAuth2Config {
+            token_url: token_url.to_string(),
+            client_id: "listener".to_string(),
+            client_secret: Some("shh".to_string()),
+            scope: None,
+            refresh_token: Some("configured".to_string()),
+            token_file: token_file.path(),
+        }).unwrap()
+    }
+
+    fn store(token_file: &TempFile, access_token: &str, expires_in: Option<i64>) {
+        let token = StoredToken {
+            access_token: Some(access_token.to_string()),
+            refresh_token: Some("stored".to_string()),
+            expires_at: expires_in.map(|secs| chrono::offset::Utc::now().timestamp() + secs),
+        };
+        fs::write(&token_file.0, serde_json::to_string(&token).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn uses_stored_tokens_until_they_expire() {
+        let token_file = TempFile::new("token-cached");
+        assert_eq!(manager(&token_file, "").cached(), None);
+
+        store(&token_file, "valid", Some(3600));
+        let mut tokens = manager(&token_file, "");
+        assert_eq!(tokens.cached().as_deref(), Some("valid"));
+        assert_eq!(tokens.access_token().unwrap(), "valid");
+        tokens.invalidate();
+        assert_eq!(tokens.cached(), None);
+
+        // Renewed a while before it really expires
+        store(&token_file, "expiring", Some(EXPIRY_MARGIN_SECS - 10));
+        assert_eq!(manager(&token_file, "").cached(), None);
+        store(&token_file, "lasting", None);
+        assert_eq!(manager(&token_file, "").cached().as_deref(), Some("lasting"));
+    }
+
+    #[test]
+    fn refreshes_and_saves_tokens() {
+        let (url, requests) = testing::http_server(&[
+            (200, r#"{"access_token": "fresh", "expires_in": 3600, "refresh_token": "rotated"}"#),
+        ]);
+        let token_file = TempFile::new("token-refresh");
+        store(&token_file, "expiring", Some(10));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&token_file.0, fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        assert_eq!(manager(&token_file, &url).access_token().unwrap(), "fresh");
+        let request = requests.recv().unwrap();
+        assert!(request.ends_with("grant_type=refresh_token&refresh_token=stored&client_id=listener&client_secret=shh"), "{request}");
+
+        let saved: StoredToken = serde_json::from_str(&fs::read_to_string(&token_file.0).unwrap()).unwrap();
+        assert_eq!(saved.access_token.as_deref(), Some("fresh"));
+        assert_eq!(saved.refresh_token.as_deref(), Some("rotated"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&token_file.0).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn keeps_tokens_when_refresh_fails() {
+        let (url, _requests) = testing::http_server(&[(400, r#"{"error": "invalid_grant"}"#)]);
+        let token_file = TempFile::new("token-failed");
+        store(&token_file, "expiring", Some(10));
+        let before = fs::read_to_string(&token_file.0).unwrap();
+
+        assert!(manager(&token_file, &url).access_token().is_err());
+        assert_eq!(fs::read_to_string(&token_file.0).unwrap(), before);
+    }
+
+    #[test]
+    fn encodes_sasl_responses() {
+        let authenticator = |mechanism| OAuth2Authenticator {
+            mechanism,
+            user: "jane@example.com".to_string(),
+            access_token: "token".to_string(),
+            host: "imap.example.com".to_string(),
+            port: 993,
+        };
+        assert_eq!(
+            authenticator(Mechanism::Xoauth2).process(b""),
+            "user=jane@example.com\x01auth=Bearer token\x01\x01",
+        );
+        assert_eq!(
+            authenticator(Mechanism::Oauthbearer).process(b""),
+            "n,a=jane@example.com,\x01host=imap.example.com\x01port=993\x01auth=Bearer token\x01\x01",
+        );
+        // Error details from the server are answered with an empty response
+        assert_eq!(authenticator(Mechanism::Xoauth2).process(br#"{"status":"401"}"#), "");
+    }
+}
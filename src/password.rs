@@ -0,0 +1,174 @@
+use std::error::Error;
+use std::fs;
+use std::io::IsTerminal;
+use std::process::Command;
+
+/// Where the account password comes from.
+#[derive(Debug, Clone)]
+pub enum PasswordSource {
+    // Given directly on the command line or in the config file
+    Plain(String),
+    // Name of environment variable holding the password
+    Env(String),
+    // File with the password on its first line, not readable by group or others
+    File(String),
+    // Shell command printing the password on its first line, e.g. `pass show mail/work`
+    Command(String),
+    // Ask on the terminal
+    Prompt,
+}
+
+impl PasswordSource {
+    /// Picks the single configured source, or the interactive prompt when none is
+    /// configured and stdin is a terminal.
+    pub fn choose(
+        plain: Option<String>,
+        env: Option<String>,
+        file: Option<String>,
+        command: Option<String>,
+    ) -> Result<PasswordSource, String> {
+        let mut sources: Vec<PasswordSource> = [
+            plain.map(PasswordSource::Plain),
+            env.map(PasswordSource::Env),
+            file.map(PasswordSource::File),
+            command.map(PasswordSource::Command),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        match sources.len() {
+            0 if std::io::stdin().is_terminal() => Ok(PasswordSource::Prompt),
+            0 => Err("missing password (set password, password_env, password_file or password_command)".to_string()),
+            1 => Ok(sources.remove(0)),
+            _ => Err("only one of password, password_env, password_file and password_command can be used".to_string()),
+        }
+    }
+
+    /// Checks the source without running commands or prompting.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            PasswordSource::Env(name) if std::env::var_os(name).is_none() => {
+                Err(format!("environment variable {name} is not set"))
+            },
+            PasswordSource::File(filepath) => check_permissions(filepath),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn resolve(&self, username: &str) -> Result<String, Box<dyn Error>> {
+        match self {
+            PasswordSource::Plain(password) => Ok(password.clone()),
+            PasswordSource::Env(name) => Ok(std::env::var(name)
+                .map_err(|e| format!("failed to read environment variable {name}: {e}"))?),
+            PasswordSource::File(filepath) => {
+                check_permissions(filepath)?;
+                Ok(first_line(&fs::read_to_string(filepath)?))
+            },
+            PasswordSource::Command(command) => {
+                let output = Command::new("sh").arg("-c").arg(command).output()?;
+                if !output.status.success() {
+                    return Err(format!("password command exited with {}", output.status).into());
+                }
+                Ok(first_line(&String::from_utf8(output.stdout)?))
+            },
+            PasswordSource::Prompt => Ok(rpassword::prompt_password(format!("Password for {username}: "))?),
+        }
+    }
+}
+
+fn first_line(contents: &str) -> String {
+    contents.lines().next().unwrap_or_default().to_string()
+}
+
+#[cfg(unix)]
+fn check_permissions(filepath: &str) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = fs::metadata(filepath).map_err(|e| format!("failed to read {filepath}: {e}"))?;
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(format!("password file {filepath} is accessible by group or others (mode {:o}), use chmod 600", mode & 0o777));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(filepath: &str) -> Result<(), String> {
+    fs::metadata(filepath).map(|_| ()).map_err(|e| format!("failed to read {filepath}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Password file of its own with the given mode, removed again when the test is done with it
+    struct PasswordFile(String);
+
+    impl PasswordFile {
+        fn new(name: &str, mode: u32) -> PasswordFile {
+            let path = std::env::temp_dir().join(format!("imap-listener-password-{name}-{}", std::process::id()));
+            fs::write(&path, "secret\nignored\n").unwrap();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            }
+            #[cfg(not(unix))]
+            let _ = mode;
+            PasswordFile(path.display().to_string())
+        }
+    }
+
+    impl Drop for PasswordFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn reads_private_password_files() {
+        let file = PasswordFile::new("private", 0o600);
+        let source = PasswordSource::File(file.0.clone());
+        assert_eq!(source.validate(), Ok(()));
+        assert_eq!(source.resolve("jane").unwrap(), "secret");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_password_files_others_can_read() {
+        let file = PasswordFile::new("shared", 0o644);
+        let source = PasswordSource::File(file.0.clone());
+        let problem = source.validate().unwrap_err();
+        assert!(problem.contains("accessible by group or others (mode 644)"), "{problem}");
+        assert!(source.resolve("jane").is_err());
+
+        let missing = PasswordSource::File(format!("{}.missing", file.0));
+        assert!(missing.validate().unwrap_err().starts_with("failed to read "));
+    }
+
+    #[test]
+    fn rejects_unset_environment_variables() {
+        let source = PasswordSource::Env("IMAP_LISTENER_TEST_NO_PASSWORD".to_string());
+        assert_eq!(source.validate(), Err("environment variable IMAP_LISTENER_TEST_NO_PASSWORD is not set".to_string()));
+        assert!(source.resolve("jane").is_err());
+    }
+
+    #[test]
+    fn runs_password_commands() {
+        let source = PasswordSource::Command("printf 'secret\\nignored\\n'".to_string());
+        assert_eq!(source.resolve("jane").unwrap(), "secret");
+
+        let failing = PasswordSource::Command("echo secret; exit 3".to_string());
+        let error = failing.resolve("jane").unwrap_err().to_string();
+        assert!(error.starts_with("password command exited with"), "{error}");
+    }
+
+    #[test]
+    fn takes_a_single_source() {
+        let chosen = PasswordSource::choose(None, Some("PASSWORD".to_string()), None, None).unwrap();
+        assert!(matches!(chosen, PasswordSource::Env(name) if name == "PASSWORD"));
+        let problem = PasswordSource::choose(Some("secret".to_string()), None, None, Some("pass".to_string())).unwrap_err();
+        assert!(problem.starts_with("only one of"));
+    }
+}
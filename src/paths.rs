@@ -0,0 +1,131 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Replaces a leading "~" with the home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    let home = std::env::var_os("HOME");
+    match (path, path.strip_prefix("~/"), home) {
+        ("~", _, Some(home)) => PathBuf::from(home),
+        (_, Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Replaces "$NAME" and "${NAME}" with environment variables, then a leading "~" with
+/// the home directory. Unset variables are an error rather than silently left out.
+pub fn expand(path: &str) -> Result<PathBuf, String> {
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let (name, length) = match rest.strip_prefix('{') {
+            Some(braced) => {
+                let end = braced.find('}').ok_or_else(|| format!("unclosed ${{ in {path}"))?;
+                (&braced[..end], end + 2)
+            },
+            None => {
+                let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+                (&rest[..end], end)
+            },
+        };
+        if name.is_empty() {
+            // Lone "$", kept as it is
+            expanded.push('$');
+            continue;
+        }
+
+        let value = std::env::var(name).map_err(|_| format!("environment variable {name} in {path} is not set"))?;
+        expanded.push_str(&value);
+        rest = &rest[length..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expand_home(&expanded))
+}
+
+/// Replaces the file with the given contents, readable only by its owner. They are
+/// written to a temporary file first, so that a crash never leaves half of them behind.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temporary_path = path.with_extension("tmp");
+    // A leftover file would keep its permissions
+    match fs::remove_file(&temporary_path) {
+        Ok(()) => {},
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temporary_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temporary_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Variables only these tests read, so that setting them doesn't disturb other tests
+    fn set_variables() {
+        std::env::set_var("IMAP_LISTENER_TEST_DIR", "/srv/sounds");
+        std::env::remove_var("IMAP_LISTENER_TEST_UNSET");
+    }
+
+    #[test]
+    fn expands_home() {
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(expand_home("~"), PathBuf::from(&home));
+        assert_eq!(expand_home("~/Music/a.ogg"), Path::new(&home).join("Music/a.ogg"));
+        // Other users' homes aren't looked up
+        assert_eq!(expand_home("~jane/a.ogg"), PathBuf::from("~jane/a.ogg"));
+        assert_eq!(expand_home("/a/~/b"), PathBuf::from("/a/~/b"));
+    }
+
+    #[test]
+    fn expands_variables() {
+        set_variables();
+        assert_eq!(expand("$IMAP_LISTENER_TEST_DIR/beep.wav").unwrap(), PathBuf::from("/srv/sounds/beep.wav"));
+        assert_eq!(expand("${IMAP_LISTENER_TEST_DIR}x.wav").unwrap(), PathBuf::from("/srv/soundsx.wav"));
+        assert_eq!(expand("a$/b$").unwrap(), PathBuf::from("a$/b$"));
+    }
+
+    #[test]
+    fn rejects_unset_variables() {
+        set_variables();
+        let error = expand("$IMAP_LISTENER_TEST_UNSET/beep.wav").unwrap_err();
+        assert!(error.contains("IMAP_LISTENER_TEST_UNSET"), "{error}");
+        assert!(expand("${IMAP_LISTENER_TEST_DIR").unwrap_err().contains("unclosed"));
+    }
+
+    #[test]
+    fn writes_private_files() {
+        let path = std::env::temp_dir().join(format!("imap-listener-private-{}.json", std::process::id()));
+        fs::write(&path, "old").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        write_private(&path, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!path.with_extension("tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // An existing file doesn't keep its permissions
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
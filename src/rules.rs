@@ -0,0 +1,469 @@
+use email::rfc2047::decode_rfc2047;
+use serde::Deserialize;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::actions::{ActionKind, MoveMail, PlaySound, RuleAction};
+use crate::smtp::{self, ForwardMode};
+use crate::address::{Address, AddressPattern};
+use crate::attachments::AttachmentFilter;
+use crate::folder::MoveTarget;
+use crate::mime::Attachment;
+use crate::text::{SearchPattern, TextPattern};
+use crate::verification::{Authentication, VerificationMethod};
+
+/// Everything a rule can look at when deciding whether a mail matches.
+#[derive(Debug)]
+pub struct Message {
+    pub uid: u32,
+    pub from: Vec<Address>,
+    pub sender: Vec<Address>,
+    pub reply_to: Vec<Address>,
+    pub subject: String,
+    // "<id@host>", for replies
+    pub message_id: Option<String>,
+    pub date: chrono::DateTime<chrono::Utc>,
+    // Raw header block, or only the fields named by header conditions; fetched
+    // when some rule has a header or verified condition
+    pub headers: Option<String>,
+    // Decoded text parts, only fetched when some rule has a body condition
+    pub body: Option<String>,
+    // Files attached to the mail, only looked up when some rule deals with attachments
+    pub attachments: Vec<Attachment>,
+    // Only filled in when some rule has a verified condition
+    pub authentication: Authentication,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    // Every sub-condition has to match
+    All(Vec<Condition>),
+    // At least one sub-condition has to match
+    Any(Vec<Condition>),
+    // Inverts the sub-condition
+    Not(Box<Condition>),
+    // Some From address matches one of the listed names, addresses, domains or regexes
+    Sender(Vec<AddressPattern>),
+    // Like sender, but checks the given address header
+    Address { field: AddressField, patterns: Vec<AddressPattern> },
+    // Subject matches one of the listed patterns; plain strings match within edit distance 2
+    Subject(Vec<TextPattern>),
+    // Text of the mail matches one of the listed patterns; plain strings are searched for anywhere
+    Body(Vec<SearchPattern>),
+    // Named header is present and, when a value is given, one of its values matches it;
+    // a plain string value is searched for anywhere. Only the named headers are fetched
+    Header {
+        name: String,
+        #[serde(default, alias = "contains")]
+        value: Option<SearchPattern>,
+    },
+    // Mail is not older than this many seconds
+    MaxAgeSecs(u32),
+    // One of the listed methods passed for the From domain: a DKIM signature of that
+    // domain or its parent, or SPF for an envelope sender in it
+    Verified(Vec<VerificationMethod>),
+    // Mail has an attachment passing every given criterion, e.g. a PDF of at most 1 MB
+    Attachment(AttachmentFilter),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressField {
+    From,
+    Sender,
+    ReplyTo,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub condition: Condition,
+    // Run in order, see actions.rs for what's available
+    pub actions: Vec<RuleAction>,
+    // Don't evaluate rules after this one if it matched
+    #[serde(default)]
+    pub stop: bool,
+}
+
+#[cfg(test)]
+impl Message {
+    /// Mail from "Jane Doe <jane@caterer.pl>" with only the envelope filled in, for tests.
+    pub fn example() -> Message {
+        Message {
+            uid: 42,
+            from: vec![Address {
+                name: Some("Jane Doe".to_string()),
+                mailbox: Some("jane".to_string()),
+                host: Some("caterer.pl".to_string()),
+            }],
+            sender: Vec::new(),
+            reply_to: Vec::new(),
+            subject: "Menu".to_string(),
+            message_id: Some("<menu-42@caterer.pl>".to_string()),
+            date: chrono::DateTime::parse_from_rfc3339("2024-03-07T12:00:00Z").unwrap().into(),
+            headers: None,
+            body: None,
+            attachments: Vec::new(),
+            authentication: Authentication::default(),
+        }
+    }
+}
+
+impl Message {
+    pub fn addresses(&self, field: AddressField) -> &[Address] {
+        match field {
+            AddressField::From => &self.from,
+            AddressField::Sender => &self.sender,
+            AddressField::ReplyTo => &self.reply_to,
+        }
+    }
+
+    /// Values of every header with the given name, among those that were fetched.
+    pub fn header_values(&self, name: &str) -> Vec<String> {
+        self.headers.as_deref().map(|headers| header_values(headers, name)).unwrap_or_default()
+    }
+
+    /// From addresses for logging.
+    pub fn senders_text(&self) -> String {
+        self.from.iter().map(Address::to_string).collect::<Vec<_>>().join(", ")
+    }
+}
+
+pub fn parse_json_to_vector(filepath: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let file = File::open(filepath)?;
+    let reader = BufReader::new(file);
+
+    let vector = serde_json::from_reader(reader)?;
+
+    Ok(vector)
+}
+
+pub fn load_rules(filepath: &str) -> Result<Vec<Rule>, Box<dyn Error>> {
+    let file = File::open(filepath)?;
+    let reader = BufReader::new(file);
+
+    let rules = serde_json::from_reader(reader)?;
+
+    Ok(rules)
+}
+
+/// Builds the rules equivalent to the original hard-coded behaviour: mails from allowed
+/// people with a triggering subject are moved to "Jedzenie", and a sound is played when
+/// they are still fresh.
+pub fn legacy_rules(
+    allowed_people: Vec<String>,
+    triggering_subjects: Vec<String>,
+    mail_expiration_secs: u32,
+) -> Result<Vec<Rule>, Box<dyn Error>> {
+    let allowed_people = allowed_people.into_iter().map(AddressPattern::try_from).collect::<Result<_, _>>()?;
+    let triggering_subjects = triggering_subjects.iter().map(|subject| TextPattern::fuzzy(subject)).collect();
+    let matches = vec![Condition::Sender(allowed_people), Condition::Subject(triggering_subjects)];
+
+    let mut notify_conditions = matches.clone();
+    notify_conditions.push(Condition::MaxAgeSecs(mail_expiration_secs));
+
+    Ok(vec![
+        Rule {
+            name: "notify".to_string(),
+            condition: Condition::All(notify_conditions),
+            actions: vec![ActionKind::PlaySound(PlaySound::default()).into()],
+            stop: false,
+        },
+        Rule {
+            name: "move".to_string(),
+            condition: Condition::All(matches),
+            actions: vec![ActionKind::Move(MoveMail(MoveTarget {
+                folder: "Jedzenie".to_string(),
+                create: false,
+                subscribe: false,
+            })).into()],
+            stop: true,
+        },
+    ])
+}
+
+/// Returns a description of every problem found in the rules.
+pub fn validate_rules(rules: &[Rule]) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if rules.is_empty() {
+        problems.push("no rules defined".to_string());
+    }
+
+    for (index, rule) in rules.iter().enumerate() {
+        if rule.name.is_empty() {
+            problems.push(format!("rule #{} has no name", index + 1));
+        }
+        if rules[..index].iter().any(|other| other.name == rule.name) {
+            problems.push(format!("rule name \"{}\" is used more than once", rule.name));
+        }
+        if rule.actions.is_empty() {
+            problems.push(format!("rule \"{}\" has no actions", rule.name));
+        }
+        for action in &rule.actions {
+            action.validate(&rule.name, &mut problems);
+        }
+        rule.condition.validate(&rule.name, &mut problems);
+    }
+
+    problems
+}
+
+impl Condition {
+    fn validate(&self, rule_name: &str, problems: &mut Vec<String>) {
+        match self {
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                if conditions.is_empty() {
+                    problems.push(format!("rule \"{rule_name}\" has an empty all/any condition"));
+                }
+                conditions.iter().for_each(|c| c.validate(rule_name, problems));
+            },
+            Condition::Not(condition) => condition.validate(rule_name, problems),
+            Condition::Header { name, .. } if name.is_empty() => {
+                problems.push(format!("rule \"{rule_name}\" has a header condition without header name"));
+            },
+            Condition::Header { name, .. } if !name.bytes().all(header_name_char) => {
+                problems.push(format!("rule \"{rule_name}\" has a header condition with invalid header name {name}"));
+            },
+            Condition::Verified(methods) if methods.is_empty() => {
+                problems.push(format!("rule \"{rule_name}\" has a verified condition without methods"));
+            },
+            Condition::Attachment(filter) => filter.validate(rule_name, problems),
+            _ => {},
+        }
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        match self {
+            Condition::All(conditions) => conditions.iter().all(|c| c.matches(message)),
+            Condition::Any(conditions) => conditions.iter().any(|c| c.matches(message)),
+            Condition::Not(condition) => !condition.matches(message),
+            Condition::Sender(patterns) => address_allowed(&message.from, patterns),
+            Condition::Address { field, patterns } => address_allowed(message.addresses(*field), patterns),
+            Condition::Subject(patterns) => patterns.iter().any(|pattern| pattern.matches(&message.subject)),
+            Condition::Body(patterns) => match &message.body {
+                Some(body) => patterns.iter().any(|pattern| pattern.matches(body)),
+                None => false,
+            },
+            Condition::Header { name, value } => match (&message.headers, value) {
+                (Some(headers), Some(pattern)) => header_values(headers, name).iter().any(|v| pattern.matches(v)),
+                (Some(headers), None) => !header_values(headers, name).is_empty(),
+                (None, _) => false,
+            },
+            Condition::MaxAgeSecs(limit_secs) => !mail_too_old(message.date, *limit_secs),
+            Condition::Verified(methods) => {
+                methods.iter().any(|method| message.authentication.verifies(*method, &message.from))
+            },
+            Condition::Attachment(filter) => message.attachments.iter().any(|attachment| filter.matches(attachment)),
+        }
+    }
+
+    fn needs(&self, leaf: &dyn Fn(&Condition) -> bool) -> bool {
+        match self {
+            Condition::All(conditions) | Condition::Any(conditions) => conditions.iter().any(|c| c.needs(leaf)),
+            Condition::Not(condition) => condition.needs(leaf),
+            other => leaf(other),
+        }
+    }
+
+    fn header_names(&self, names: &mut Vec<String>) {
+        match self {
+            Condition::All(conditions) | Condition::Any(conditions) => {
+                conditions.iter().for_each(|c| c.header_names(names));
+            },
+            Condition::Not(condition) => condition.header_names(names),
+            Condition::Header { name, .. } if !names.iter().any(|known| known.eq_ignore_ascii_case(name)) => {
+                names.push(name.clone());
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Names of the headers that header conditions look at, empty when there are none.
+pub fn rules_header_names(rules: &[Rule]) -> Vec<String> {
+    let mut names = Vec::new();
+    rules.iter().for_each(|rule| rule.condition.header_names(&mut names));
+    if rules_send_mail(rules) {
+        for name in smtp::HEADERS {
+            if !names.iter().any(|known| known.eq_ignore_ascii_case(name)) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names
+}
+
+/// Whether any rule forwards or answers mail.
+pub fn rules_send_mail(rules: &[Rule]) -> bool {
+    rules.iter().any(|rule| {
+        rule.actions.iter().any(|action| matches!(action.kind, ActionKind::Forward(_) | ActionKind::AutoReply(_)))
+    })
+}
+
+/// Whether any rule asks where the mail really comes from.
+pub fn rules_need_verification(rules: &[Rule]) -> bool {
+    rules.iter().any(|rule| rule.condition.needs(&|c| matches!(c, Condition::Verified(_))))
+}
+
+/// Whether any rule has to look at or forward the message text.
+pub fn rules_need_body(rules: &[Rule]) -> bool {
+    rules.iter().any(|rule| {
+        rule.condition.needs(&|c| matches!(c, Condition::Body(_)))
+            || rule.actions.iter().any(|action| {
+                matches!(&action.kind, ActionKind::Forward(forward) if forward.mode == ForwardMode::Inline)
+            })
+    })
+}
+
+/// Whether any rule looks at or saves attachments.
+pub fn rules_need_attachments(rules: &[Rule]) -> bool {
+    rules.iter().any(|rule| {
+        rule.condition.needs(&|c| matches!(c, Condition::Attachment(_)))
+            || rule.actions.iter().any(|action| matches!(action.kind, ActionKind::SaveAttachments(_)))
+    })
+}
+
+fn address_allowed(addresses: &[Address], patterns: &[AddressPattern]) -> bool {
+    addresses.iter().any(|address| patterns.iter().any(|pattern| pattern.matches(address)))
+}
+
+fn mail_too_old(date: chrono::DateTime<chrono::Utc>, limit_secs: u32) -> bool {
+    // Mail dated in the future, e.g. by a skewed clock, is fresh
+    let now_date = chrono::offset::Utc::now();
+    (now_date - date).num_seconds() > i64::from(limit_secs)
+}
+
+// Printable ASCII except what can't appear in a header name or an IMAP atom
+fn header_name_char(c: u8) -> bool {
+    c.is_ascii_graphic() && !b":()%*\"\\]{".contains(&c)
+}
+
+// Returns unfolded and decoded values of every header with the given name
+fn header_values(headers: &str, name: &str) -> Vec<String> {
+    let mut values: Vec<String> = Vec::new();
+    let mut current: Option<String> = None;
+
+    for line in headers.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(value) = current.as_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some(value) = current.take() {
+            values.push(value);
+        }
+        if let Some((field, value)) = line.split_once(':') {
+            if field.trim().eq_ignore_ascii_case(name) {
+                current = Some(value.trim().to_string());
+            }
+        }
+    }
+    if let Some(value) = current {
+        values.push(value);
+    }
+
+    values.into_iter().map(|value| decode_rfc2047(&value).unwrap_or(value)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn condition(json: serde_json::Value) -> Condition {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn fresh() -> Message {
+        let mut message = Message::example();
+        message.date = chrono::offset::Utc::now();
+        message
+    }
+
+    #[test]
+    fn evaluates_conditions() {
+        let message = Message::example();
+        assert!(condition(json!({"sender": ["*@caterer.pl"]})).matches(&message));
+        assert!(!condition(json!({"sender": ["Jane"]})).matches(&message));
+        assert!(condition(json!({"subject": ["menu"]})).matches(&message));
+        assert!(condition(json!({"all": [{"sender": ["Jane Doe"]}, {"subject": ["Menus"]}]})).matches(&message));
+        assert!(!condition(json!({"all": [{"sender": ["Jane Doe"]}, {"subject": ["Invoice"]}]})).matches(&message));
+        assert!(condition(json!({"any": [{"sender": ["John"]}, {"subject": ["Menu"]}]})).matches(&message));
+        assert!(condition(json!({"not": {"subject": ["Invoice"]}})).matches(&message));
+        assert!(!condition(json!({"address": {"field": "reply_to", "patterns": ["*@caterer.pl"]}})).matches(&message));
+    }
+
+    #[test]
+    fn conditions_on_unfetched_parts_fail() {
+        let mut message = Message::example();
+        let body = condition(json!({"body": ["soup"]}));
+        let header = condition(json!({"header": {"name": "X-Priority"}}));
+        assert!(!body.matches(&message));
+        assert!(!header.matches(&message));
+
+        message.body = Some("Today: soup".to_string());
+        message.headers = Some("X-Priority: 1\r\nSubject: =?UTF-8?B?WnVwYQ==?=\r\n".to_string());
+        assert!(body.matches(&message));
+        assert!(header.matches(&message));
+        assert!(condition(json!({"header": {"name": "subject", "contains": "zupa"}})).matches(&message));
+        assert!(!condition(json!({"header": {"name": "X-Priority", "value": "5"}})).matches(&message));
+    }
+
+    #[test]
+    fn checks_mail_age() {
+        let max_age = condition(json!({"max_age_secs": 60}));
+        assert!(max_age.matches(&fresh()));
+        assert!(!max_age.matches(&Message::example()));
+
+        // A sender's clock running ahead doesn't make the mail old
+        let mut message = fresh();
+        message.date = message.date + chrono::Duration::seconds(30);
+        assert!(max_age.matches(&message));
+    }
+
+    #[test]
+    fn legacy_rules_notify_fresh_mail_and_move_all_matching() {
+        let rules = legacy_rules(vec!["Jane Doe".to_string()], vec!["Menu".to_string()], 60).unwrap();
+        assert!(validate_rules(&rules).is_empty());
+        let matching = |message: &Message| {
+            rules.iter().filter(|rule| rule.condition.matches(message)).map(|rule| rule.name.as_str()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(matching(&fresh()), ["notify", "move"]);
+        assert_eq!(matching(&Message::example()), ["move"]);
+        let mut message = fresh();
+        message.subject = "Meny".to_string();
+        assert_eq!(matching(&message), ["notify", "move"]);
+        message.subject = "Invoice".to_string();
+        assert!(matching(&message).is_empty());
+        let mut message = fresh();
+        message.from[0].name = Some("John Doe".to_string());
+        assert!(matching(&message).is_empty());
+
+        assert!(matches!(rules[0].actions[0].kind, ActionKind::PlaySound(_)));
+        assert!(matches!(&rules[1].actions[0].kind, ActionKind::Move(MoveMail(target)) if target.folder == "Jedzenie"));
+        assert!(rules[1].stop);
+
+        assert!(legacy_rules(vec!["/(/".to_string()], Vec::new(), 60).is_err());
+    }
+
+    #[test]
+    fn reports_rule_problems() {
+        let rules: Vec<Rule> = serde_json::from_value(json!([
+            {"name": "a", "condition": {"any": []}, "actions": []},
+            {"name": "a", "condition": {"header": {"name": "X:Y"}}, "actions": ["mark_read"]},
+        ])).unwrap();
+        assert_eq!(validate_rules(&rules), [
+            "rule \"a\" has no actions",
+            "rule \"a\" has an empty all/any condition",
+            "rule name \"a\" is used more than once",
+            "rule \"a\" has a header condition with invalid header name X:Y",
+        ]);
+        assert_eq!(validate_rules(&[]), ["no rules defined"]);
+    }
+}
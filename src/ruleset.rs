@@ -0,0 +1,172 @@
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::error::Error;
+use std::path::{self, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use log::{error, info, warn};
+
+use crate::config::{Config, ConfigError};
+use crate::rules::Rule;
+
+// Editors save in several steps, events this close together are taken as one change
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// Rules in force, shared by all workers and replaced as a whole when they are reloaded.
+pub struct RuleSet {
+    rules: RwLock<Arc<Vec<Rule>>>,
+}
+
+impl RuleSet {
+    /// Reads the rules and checks them; the files may have changed since the configuration
+    /// was validated.
+    pub fn load(config: &Config) -> Result<RuleSet, Box<dyn Error>> {
+        let rules = config.load_rules()?;
+        let problems = config.check_rules(&rules);
+        if !problems.is_empty() {
+            return Err(ConfigError(problems).into());
+        }
+        Ok(RuleSet { rules: RwLock::new(Arc::new(rules)) })
+    }
+
+    /// Rules to handle one mail with; a reload meanwhile doesn't change them.
+    pub fn current(&self) -> Arc<Vec<Rule>> {
+        Arc::clone(&self.rules.read().unwrap())
+    }
+
+    /// Reads the rules again and puts them in force, unless they can't be loaded or
+    /// have problems, in which case the previous ones stay.
+    pub fn reload(&self, config: &Config) {
+        let rules = match config.load_rules() {
+            Ok(rules) => rules,
+            Err(e) => {
+                error!("Failed to load rules, keeping the previous ones: {e}");
+                return;
+            },
+        };
+
+        let problems = config.check_rules(&rules);
+        if !problems.is_empty() {
+            for problem in &problems {
+                error!("Rules problem: {problem}");
+            }
+            error!("Keeping the previous rules");
+            return;
+        }
+
+        info!("Loaded {} rules", rules.len());
+        *self.rules.write().unwrap() = Arc::new(rules);
+    }
+}
+
+// Whether the event may have changed one of the files
+fn touches(event: &Event, files: &[PathBuf]) -> bool {
+    let changing = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_));
+    changing && event.paths.iter().any(|path| files.contains(path))
+}
+
+/// Reloads the rules whenever a file they come from changes. Returns right away when
+/// rules are given inline, otherwise only when watching fails.
+pub fn watch_files(rules: &RuleSet, config: &Config) -> Result<(), Box<dyn Error>> {
+    let files = config.rule_files().into_iter().map(path::absolute).collect::<Result<Vec<_>, _>>()?;
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    // Directories are watched rather than the files, since editors often save by
+    // replacing the file with a new one
+    let mut directories: Vec<PathBuf> = files.iter().filter_map(|file| file.parent()).map(PathBuf::from).collect();
+    directories.sort();
+    directories.dedup();
+    for directory in &directories {
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    }
+
+    loop {
+        match receiver.recv()? {
+            Ok(event) if touches(&event, &files) => {},
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Error watching rule files: {e}");
+                continue;
+            },
+        }
+        // Other files in the directories don't count, they may change all the time
+        let mut settled_at = Instant::now() + SETTLE_TIME;
+        while let Some(remaining) = settled_at.checked_duration_since(Instant::now()) {
+            if let Ok(Ok(event)) = receiver.recv_timeout(remaining) {
+                if touches(&event, &files) {
+                    settled_at = Instant::now() + SETTLE_TIME;
+                }
+            }
+        }
+
+        info!("Rule files changed, reloading rules");
+        rules.reload(config);
+    }
+}
+
+/// Reloads the rules on every SIGHUP.
+pub fn reload_on_hangup(rules: &RuleSet, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut signals = Signals::new([SIGHUP])?;
+    for _ in signals.forever() {
+        info!("Got SIGHUP, reloading rules");
+        rules.reload(config);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use structopt::StructOpt;
+    use crate::testing::TempFile;
+    use crate::Opt;
+
+    fn rules(name: &str) -> String {
+        format!(r#"[{{"name": "{name}", "condition": {{"sender": ["*@caterer.pl"]}}, "actions": ["mark_read"]}}]"#)
+    }
+
+    fn names(rules: &RuleSet) -> Vec<String> {
+        rules.current().iter().map(|rule| rule.name.clone()).collect()
+    }
+
+    #[test]
+    fn reload_keeps_previous_rules_when_new_ones_are_invalid() {
+        let rules_file = TempFile::new("ruleset-rules");
+        let config_file = TempFile::new("ruleset-config");
+        fs::write(&rules_file.0, rules("catering")).unwrap();
+        let toml = format!("server = \"imap.example.com\"\nusername = \"jane\"\npassword = \"secret\"\nrules_file = {:?}\n", rules_file.path());
+        fs::write(&config_file.0, toml).unwrap();
+        let config = Config::load(&Opt::from_iter(["idle", "--config", &config_file.path()])).unwrap();
+        let rule_set = RuleSet::load(&config).unwrap();
+        let before = rule_set.current();
+
+        // Not JSON
+        fs::write(&rules_file.0, "[{\"name\": ").unwrap();
+        rule_set.reload(&config);
+        assert_eq!(names(&rule_set), ["catering"]);
+
+        // Readable, but with problems
+        fs::write(&rules_file.0, r#"[{"name": "empty", "condition": {"any": []}, "actions": ["mark_read"]}]"#).unwrap();
+        rule_set.reload(&config);
+        assert_eq!(names(&rule_set), ["catering"]);
+
+        // Gone
+        fs::remove_file(&rules_file.0).unwrap();
+        rule_set.reload(&config);
+        assert_eq!(names(&rule_set), ["catering"]);
+
+        fs::write(&rules_file.0, rules("lunch")).unwrap();
+        rule_set.reload(&config);
+        assert_eq!(names(&rule_set), ["lunch"]);
+        // Mail being handled keeps the rules it started with
+        assert_eq!(before[0].name, "catering");
+    }
+
+}
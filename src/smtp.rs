@@ -0,0 +1,686 @@
+use lettre::address::Envelope;
+use lettre::message::header::{ContentTransferEncoding, ContentType, Header, HeaderName, HeaderValue};
+use lettre::message::{Attachment, Body, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SmtpTransport, Transport};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use log::{info, warn};
+
+use crate::actions::{Action, ActionContext, Outcome};
+use crate::attachments::safe_filename;
+use crate::error::ActionError;
+use crate::password::PasswordSource;
+use crate::paths;
+use crate::rules::Message;
+use crate::template::{Context, Template};
+use crate::worker;
+
+/// Headers forward and auto_reply actions look at, fetched whenever a rule has one.
+pub const HEADERS: [&str; 4] = ["Auto-Submitted", "Precedence", "List-Id", "References"];
+
+// Longest auto_reply interval, replies older than this are forgotten
+const MAX_INTERVAL_SECS: u64 = 366 * 24 * 60 * 60;
+
+/// How the connection to the SMTP server is secured.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Security {
+    // Plain connection upgraded with STARTTLS, which has to succeed
+    #[default]
+    Starttls,
+    // TLS from the start
+    Tls,
+    // No encryption at all; only for a relay on the same machine or a test sink
+    None,
+}
+
+impl Security {
+    pub fn default_port(self) -> u16 {
+        match self {
+            Security::Starttls => 587,
+            Security::Tls => 465,
+            Security::None => 25,
+        }
+    }
+}
+
+/// Where mail of forward and auto_reply actions is sent through.
+#[derive(Debug)]
+pub struct SmtpConfig {
+    pub server: String,
+    pub port: u16,
+    pub security: Security,
+    // Login, when the server wants one
+    pub credentials: Option<(String, PasswordSource)>,
+    // Sender of every mail, e.g. "Mail bot <bot@example.com>"
+    pub from: String,
+    pub timeout_secs: u64,
+    // Who got an auto-reply when, kept across restarts for the per-sender limit
+    pub replies_file: String,
+}
+
+impl SmtpConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.server.is_empty() {
+            problems.push("smtp.server can't be empty".to_string());
+        }
+        if let Err(e) = self.from.parse::<Mailbox>() {
+            problems.push(format!("smtp.from {} is not a valid address: {e}", self.from));
+        }
+        if self.timeout_secs == 0 {
+            problems.push("smtp.timeout_secs has to be greater than 0".to_string());
+        }
+        if let Some(Err(problem)) = self.credentials.as_ref().map(|(_, password)| password.validate()) {
+            problems.push(format!("smtp: {problem}"));
+        }
+        if self.security == Security::None && self.credentials.is_some() && !loopback(&self.server) {
+            problems.push(format!(
+                "smtp.security = \"none\" would send the password to {} unencrypted, use it only with a server on this machine",
+                self.server,
+            ));
+        }
+        problems
+    }
+}
+
+// Server on this machine, where an unencrypted connection never leaves it
+fn loopback(server: &str) -> bool {
+    let address = server.strip_prefix('[').and_then(|server| server.strip_suffix(']')).unwrap_or(server);
+    server.eq_ignore_ascii_case("localhost") || address.parse::<IpAddr>().is_ok_and(|address| address.is_loopback())
+}
+
+// RFC 3834 marker, so that responders elsewhere leave our mail alone
+#[derive(Clone)]
+struct AutoSubmitted(String);
+
+impl Header for AutoSubmitted {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("Auto-Submitted")
+    }
+
+    fn parse(value: &str) -> Result<AutoSubmitted, Box<dyn Error + Send + Sync>> {
+        Ok(AutoSubmitted(value.to_string()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), self.0.clone())
+    }
+}
+
+// Value of a header without its parameters, e.g. "auto-replied" out of "auto-replied; x=y"
+fn header_token(value: &str) -> String {
+    value.split(';').next().unwrap_or_default().trim().to_lowercase()
+}
+
+// Why the mail looks automatic and mustn't be answered automatically (RFC 3834), if it does
+fn automatic(message: &Message, own_address: &str) -> Option<String> {
+    if let Some(value) = message.header_values("Auto-Submitted").iter().find(|value| header_token(value) != "no") {
+        return Some(format!("it's Auto-Submitted: {value}"));
+    }
+    if let Some(value) = message.header_values("Precedence").iter()
+        .find(|value| ["bulk", "list", "junk"].contains(&header_token(value).as_str()))
+    {
+        return Some(format!("it has Precedence: {value}"));
+    }
+    if !message.header_values("List-Id").is_empty() {
+        return Some("it comes from a mailing list".to_string());
+    }
+    for address in message.from.iter().chain(&message.sender) {
+        let local_part = address.mailbox.as_deref().unwrap_or_default().to_lowercase();
+        if ["mailer-daemon", "postmaster"].contains(&local_part.as_str()) || local_part.starts_with("owner-") {
+            return Some(format!("it comes from {address}"));
+        }
+        if address.address().is_some_and(|address| address.eq_ignore_ascii_case(own_address)) {
+            return Some("it comes from smtp.from".to_string());
+        }
+    }
+    None
+}
+
+// Our own forward coming back, or somebody else's; either way a loop is likely
+fn already_forwarded(message: &Message) -> bool {
+    message.header_values("Auto-Submitted").iter().any(|value| header_token(value) == "auto-forwarded")
+}
+
+/// Sends mail for the actions, and remembers who got an auto-reply. Shared by all workers.
+pub struct Mailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+    replies_path: PathBuf,
+    // Time of the last auto-reply, by lowercase address
+    replies: Mutex<BTreeMap<String, chrono::DateTime<chrono::Utc>>>,
+}
+
+impl Mailer {
+    /// Sets up the transport, asking for the password when it comes from a prompt; the
+    /// server is only connected to once there's something to send.
+    pub fn new(config: &SmtpConfig) -> Result<Mailer, Box<dyn Error>> {
+        let builder = match config.security {
+            Security::Starttls => SmtpTransport::starttls_relay(&config.server)?,
+            Security::Tls => SmtpTransport::relay(&config.server)?,
+            Security::None => SmtpTransport::builder_dangerous(&config.server),
+        };
+        let mut builder = builder.port(config.port).timeout(Some(Duration::from_secs(config.timeout_secs)));
+        if let Some((username, password)) = &config.credentials {
+            builder = builder.credentials(Credentials::new(username.clone(), password.resolve(username)?));
+        }
+
+        let replies = match fs::read_to_string(&config.replies_file) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Mailer {
+            transport: builder.build(),
+            from: config.from.parse()?,
+            replies_path: PathBuf::from(&config.replies_file),
+            replies: Mutex::new(replies),
+        })
+    }
+
+    fn builder(&self) -> lettre::message::MessageBuilder {
+        lettre::Message::builder().from(self.from.clone())
+    }
+
+    // Takes the sender's turn for an auto-reply, false when they had one too recently
+    fn claim(&self, address: &str, interval_secs: u64) -> bool {
+        let mut replies = self.replies.lock().unwrap();
+        let now = chrono::offset::Utc::now();
+        let interval = chrono::Duration::seconds(interval_secs as i64);
+        if replies.get(address).is_some_and(|last| now - *last < interval) {
+            return false;
+        }
+        replies.insert(address.to_string(), now);
+        true
+    }
+
+    // Gives the turn back after the reply couldn't be sent
+    fn release(&self, address: &str) {
+        self.replies.lock().unwrap().remove(address);
+    }
+
+    fn save_replies(&self) -> Result<(), Box<dyn Error>> {
+        let mut replies = self.replies.lock().unwrap();
+        let oldest = chrono::offset::Utc::now() - chrono::Duration::seconds(MAX_INTERVAL_SECS as i64);
+        replies.retain(|_, last| *last > oldest);
+
+        // Addresses of correspondents are nobody else's business
+        paths::write_private(&self.replies_path, serde_json::to_string_pretty(&*replies)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn mailer<'a>(context: &ActionContext<'a>) -> Result<&'a Mailer, ActionError> {
+    context.mailer.ok_or_else(|| ActionError::Failed("there's no [smtp] section to send mail with".into()))
+}
+
+/// How forward actions pass the mail on.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardMode {
+    // Attached unchanged, attachments and all
+    #[default]
+    Attachment,
+    // Text quoted below the sender, date and subject; attachments are left out
+    Inline,
+}
+
+/// Settings of the forward action.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Forward {
+    pub to: Vec<String>,
+    #[serde(default)]
+    pub mode: ForwardMode,
+    // "Fwd: <subject>" when not given
+    pub subject: Option<Template>,
+    // Text put above the forwarded mail
+    pub note: Option<Template>,
+}
+
+impl Forward {
+    pub fn validate(&self, rule_name: &str, problems: &mut Vec<String>) {
+        if self.to.is_empty() {
+            problems.push(format!("rule \"{rule_name}\" forwards mail to nobody"));
+        }
+        for to in &self.to {
+            if let Err(e) = to.parse::<Mailbox>() {
+                problems.push(format!("rule \"{rule_name}\" forwards mail to invalid address {to}: {e}"));
+            }
+        }
+    }
+
+    // Sends the mail on; raw is the whole original, needed when attaching it
+    fn send(&self, mailer: &Mailer, message: &Message, values: &Context<'_>, raw: Option<Vec<u8>>) -> Result<(), Box<dyn Error>> {
+        let subject = match &self.subject {
+            Some(subject) => subject.render(values, |value| value),
+            None => format!("Fwd: {}", message.subject),
+        };
+        let note = self.note.as_ref().map(|note| note.render(values, |value| value)).unwrap_or_default();
+
+        let mut builder = mailer.builder().subject(subject).header(AutoSubmitted("auto-forwarded".to_string()));
+        for to in &self.to {
+            builder = builder.to(to.parse()?);
+        }
+        let email = match raw {
+            Some(raw) => {
+                // RFC 2046 only allows identity encodings for attached mails, base64 is
+                // a last resort for ones with overlong lines
+                let body = Body::new_with_encoding(raw, ContentTransferEncoding::EightBit).unwrap_or_else(Body::new);
+                let name = safe_filename(&message.subject);
+                let name = if name.is_empty() { "forwarded.eml".to_string() } else { format!("{name}.eml") };
+                let content_type = ContentType::parse("message/rfc822").expect("valid MIME type");
+                builder.multipart(
+                    MultiPart::mixed()
+                        .singlepart(SinglePart::plain(note))
+                        .singlepart(Attachment::new(name).body(body, content_type)),
+                )?
+            },
+            None => builder.header(ContentType::TEXT_PLAIN).body(format!(
+                "{}---------- Forwarded message ----------\nFrom: {}\nDate: {}\nSubject: {}\n\n{}",
+                if note.is_empty() { String::new() } else { format!("{note}\n\n") },
+                message.senders_text(),
+                message.date.to_rfc2822(),
+                message.subject,
+                message.body.as_deref().unwrap_or_default(),
+            ))?,
+        };
+
+        mailer.transport.send(&email)?;
+        Ok(())
+    }
+}
+
+impl Action for Forward {
+    fn name(&self) -> &'static str {
+        "forward"
+    }
+
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        let mailer = mailer(context)?;
+        let message = context.message;
+        if already_forwarded(message) {
+            info!("Not forwarding mail of UID {}, it was forwarded automatically already", message.uid);
+            return Ok(Outcome::Kept);
+        }
+
+        let raw = match self.mode {
+            ForwardMode::Attachment => Some(worker::fetch_raw(context.session, message.uid)?),
+            ForwardMode::Inline => None,
+        };
+        context.watchdog.feed();
+        self.send(mailer, message, &context.template_context(), raw).map_err(ActionError::Failed)?;
+        info!("Forwarded mail of UID {} to {}", message.uid, self.to.join(", "));
+        Ok(Outcome::Kept)
+    }
+}
+
+/// Settings of the auto_reply action.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AutoReply {
+    // "Re: <subject>" when not given
+    pub subject: Option<Template>,
+    pub body: Template,
+    // Each sender gets at most one reply in this many seconds, over all rules
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_interval_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+impl AutoReply {
+    pub fn validate(&self, rule_name: &str, problems: &mut Vec<String>) {
+        if self.body.is_empty() {
+            problems.push(format!("rule \"{rule_name}\" auto-replies with an empty body"));
+        }
+        if self.interval_secs == 0 || self.interval_secs > MAX_INTERVAL_SECS {
+            problems.push(format!("rule \"{rule_name}\" auto-replies with interval_secs not between 1 and {MAX_INTERVAL_SECS}"));
+        }
+    }
+}
+
+impl AutoReply {
+    // Answers the sender, unless the mail shouldn't be answered; skipping isn't an error
+    fn reply(&self, mailer: &Mailer, message: &Message, values: &Context<'_>) -> Result<(), Box<dyn Error>> {
+        if let Some(reason) = automatic(message, mailer.from.email.as_ref()) {
+            info!("Not auto-replying to mail of UID {}, {reason}", message.uid);
+            return Ok(());
+        }
+        let Some(address) = message.reply_to.first().or(message.from.first()).and_then(|recipient| recipient.address()) else {
+            info!("Not auto-replying to mail of UID {}, it has no sender address", message.uid);
+            return Ok(());
+        };
+        let recipient = message.reply_to.first().or(message.from.first()).and_then(|recipient| recipient.name.clone());
+        let to = Mailbox::new(recipient, address.parse()?);
+
+        let subject = match &self.subject {
+            Some(subject) => subject.render(values, |value| value),
+            None if message.subject.to_lowercase().starts_with("re:") => message.subject.clone(),
+            None => format!("Re: {}", message.subject),
+        };
+        let mut builder = mailer.builder()
+            .to(to.clone())
+            .subject(subject)
+            .header(AutoSubmitted("auto-replied".to_string()));
+        if let Some(message_id) = &message.message_id {
+            let references = message.header_values("References").into_iter().chain([message_id.clone()]);
+            builder = builder.in_reply_to(message_id.clone()).references(references.collect::<Vec<_>>().join(" "));
+        }
+        let email = builder.header(ContentType::TEXT_PLAIN).body(self.body.render(values, |value| value))?;
+        // Null sender as RFC 3834 asks, so that bounces of the reply go nowhere
+        let envelope = Envelope::new(None, vec![to.email])?;
+
+        let key = address.to_lowercase();
+        if !mailer.claim(&key, self.interval_secs) {
+            info!("Not auto-replying to mail of UID {}, {address} got a reply less than {}s ago", message.uid, self.interval_secs);
+            return Ok(());
+        }
+        if let Err(e) = mailer.transport.send_raw(&envelope, &email.formatted()) {
+            mailer.release(&key);
+            return Err(e.into());
+        }
+        if let Err(e) = mailer.save_replies() {
+            warn!("Failed to write {}: {e}", mailer.replies_path.display());
+        }
+        info!("Auto-replied to {address} for mail of UID {}", message.uid);
+        Ok(())
+    }
+}
+
+impl Action for AutoReply {
+    fn name(&self) -> &'static str {
+        "auto_reply"
+    }
+
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        let mailer = mailer(context)?;
+        self.reply(mailer, context.message, &context.template_context()).map_err(ActionError::Failed)?;
+        Ok(Outcome::Kept)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn message(headers: &str) -> Message {
+        let mut message = Message::example();
+        message.headers = Some(headers.to_string());
+        message
+    }
+
+    #[test]
+    fn recognizes_automatic_mail() {
+        let own = "bot@example.com";
+        assert_eq!(automatic(&message(""), own), None);
+        assert_eq!(automatic(&message("Auto-Submitted: No\r\n"), own), None);
+        assert_eq!(automatic(&message("Precedence: first-class\r\n"), own), None);
+
+        assert_eq!(
+            automatic(&message("Auto-Submitted: auto-replied; x=y\r\n"), own).as_deref(),
+            Some("it's Auto-Submitted: auto-replied; x=y"),
+        );
+        assert!(automatic(&message("Auto-Submitted: auto-generated\r\n"), own).is_some());
+        for precedence in ["bulk", "List", "junk"] {
+            assert!(automatic(&message(&format!("Precedence: {precedence}\r\n")), own).is_some());
+        }
+        assert!(automatic(&message("List-Id: Lunch <lunch.example.com>\r\n"), own).is_some());
+
+        for local_part in ["MAILER-DAEMON", "postmaster", "owner-lunch"] {
+            let mut message = message("");
+            message.from[0].mailbox = Some(local_part.to_string());
+            assert!(automatic(&message, own).is_some(), "{local_part}");
+        }
+        // Only the sender may give it away
+        let mut message = message("");
+        message.sender = message.from.clone();
+        message.sender[0].mailbox = Some("mailer-daemon".to_string());
+        assert!(automatic(&message, own).is_some());
+
+        assert_eq!(automatic(&Message::example(), "JANE@caterer.pl").as_deref(), Some("it comes from smtp.from"));
+    }
+
+    #[test]
+    fn recognizes_forwarded_mail() {
+        assert!(already_forwarded(&message("Auto-Submitted: auto-forwarded\r\n")));
+        assert!(already_forwarded(&message("Auto-Submitted: Auto-Forwarded; by=us\r\n")));
+        assert!(!already_forwarded(&message("Auto-Submitted: auto-replied\r\n")));
+        assert!(!already_forwarded(&Message::example()));
+    }
+
+    // Replies file of its own, removed again when the test is done with it
+    struct RepliesFile(PathBuf);
+
+    impl RepliesFile {
+        fn new(name: &str) -> RepliesFile {
+            let path = std::env::temp_dir().join(format!("imap-listener-replies-{name}-{}.json", std::process::id()));
+            let _ = fs::remove_file(&path);
+            RepliesFile(path)
+        }
+    }
+
+    impl Drop for RepliesFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn mailer(port: u16, replies: &RepliesFile) -> Mailer {
+        Mailer::new(&SmtpConfig {
+            server: "127.0.0.1".to_string(),
+            port,
+            security: Security::None,
+            credentials: None,
+            from: "Mail bot <bot@example.com>".to_string(),
+            timeout_secs: 5,
+            replies_file: replies.0.display().to_string(),
+        }).unwrap()
+    }
+
+    #[test]
+    fn claims_and_releases_turns() {
+        let replies = RepliesFile::new("claim");
+        let mailer = mailer(25, &replies);
+        assert!(mailer.claim("jane@caterer.pl", 60));
+        assert!(!mailer.claim("jane@caterer.pl", 60));
+        assert!(mailer.claim("john@caterer.pl", 60));
+
+        mailer.release("jane@caterer.pl");
+        assert!(mailer.claim("jane@caterer.pl", 60));
+
+        let earlier = chrono::offset::Utc::now() - chrono::Duration::seconds(120);
+        mailer.replies.lock().unwrap().insert("jane@caterer.pl".to_string(), earlier);
+        assert!(!mailer.claim("jane@caterer.pl", 3600));
+        assert!(mailer.claim("jane@caterer.pl", 60));
+
+        // Turns outlive the mailer, in a file only the user can read
+        mailer.save_replies().unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&replies.0).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let mailer = self::mailer(25, &replies);
+        assert!(!mailer.claim("jane@caterer.pl", 60));
+    }
+
+    #[test]
+    fn refuses_to_send_passwords_unencrypted() {
+        let config = |server: &str, security: Security, credentials: bool| SmtpConfig {
+            server: server.to_string(),
+            port: security.default_port(),
+            security,
+            credentials: credentials.then(|| ("bot".to_string(), PasswordSource::Plain("secret".to_string()))),
+            from: "bot@example.com".to_string(),
+            timeout_secs: 5,
+            replies_file: "replies.json".to_string(),
+        };
+        assert!(config("smtp.example.com", Security::Starttls, true).validate().is_empty());
+        assert!(config("smtp.example.com", Security::None, false).validate().is_empty());
+        for server in ["localhost", "127.0.0.1", "127.1.2.3", "::1", "[::1]"] {
+            assert!(config(server, Security::None, true).validate().is_empty(), "{server}");
+        }
+        assert_eq!(
+            config("smtp.example.com", Security::None, true).validate(),
+            ["smtp.security = \"none\" would send the password to smtp.example.com unencrypted, use it only with a server on this machine"],
+        );
+        assert_eq!(config("10.0.0.1", Security::None, true).validate().len(), 1);
+    }
+
+    // Mail as an SMTP server got it: the envelope and the data
+    struct Received {
+        mail_from: String,
+        rcpt_to: Vec<String>,
+        data: String,
+    }
+
+    // SMTP server accepting every mail and handing it to the test
+    fn sink() -> (u16, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let reply = |line: &str| (&stream).write_all(format!("{line}\r\n").as_bytes()).unwrap();
+                reply("220 sink");
+                let mut received = Received { mail_from: String::new(), rcpt_to: Vec::new(), data: String::new() };
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 {
+                        break;
+                    }
+                    let command = line.trim_end().to_string();
+                    let verb = command.split([' ', ':']).next().unwrap_or_default().to_uppercase();
+                    match verb.as_str() {
+                        "MAIL" => received.mail_from = command,
+                        "RCPT" => received.rcpt_to.push(command),
+                        "DATA" => {
+                            reply("354 go on");
+                            loop {
+                                let mut line = String::new();
+                                reader.read_line(&mut line).unwrap();
+                                if line == ".\r\n" {
+                                    break;
+                                }
+                                received.data.push_str(&line);
+                            }
+                            sender.send(std::mem::replace(&mut received, Received {
+                                mail_from: String::new(),
+                                rcpt_to: Vec::new(),
+                                data: String::new(),
+                            })).unwrap();
+                        },
+                        "QUIT" => {
+                            reply("221 bye");
+                            break;
+                        },
+                        _ => {},
+                    }
+                    reply("250 ok");
+                }
+            }
+        });
+        (port, receiver)
+    }
+
+    fn template(text: &str) -> Template {
+        Template::try_from(text.to_string()).unwrap()
+    }
+
+    fn context(message: &Message) -> Context<'_> {
+        Context { account: "work", mailbox: "INBOX", rule: "menus", message }
+    }
+
+    #[test]
+    fn auto_replies_once_with_null_sender() {
+        let (port, received) = sink();
+        let replies = RepliesFile::new("reply");
+        let mailer = mailer(port, &replies);
+        let auto_reply = AutoReply { subject: None, body: template("Thanks for {subject}"), interval_secs: 3600 };
+        let message = message("References: <order-1@caterer.pl>\r\n");
+
+        auto_reply.reply(&mailer, &message, &context(&message)).unwrap();
+        let reply = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(reply.mail_from, "MAIL FROM:<>");
+        assert_eq!(reply.rcpt_to, ["RCPT TO:<jane@caterer.pl>"]);
+        assert!(reply.data.contains("Subject: Re: Menu\r\n"), "{}", reply.data);
+        assert!(reply.data.contains("Auto-Submitted: auto-replied\r\n"));
+        assert!(reply.data.contains("In-Reply-To: <menu-42@caterer.pl>\r\n"));
+        assert!(reply.data.contains("References: <order-1@caterer.pl> <menu-42@caterer.pl>\r\n"));
+        assert!(reply.data.contains("Thanks for Menu"));
+
+        // The sender had their reply, and automatic mail never gets one
+        auto_reply.reply(&mailer, &message, &context(&message)).unwrap();
+        let mut automatic = self::message("Auto-Submitted: auto-generated\r\n");
+        automatic.from[0].mailbox = Some("john".to_string());
+        auto_reply.reply(&mailer, &automatic, &context(&automatic)).unwrap();
+        assert!(received.recv_timeout(Duration::from_millis(500)).is_err());
+        assert!(fs::read_to_string(&replies.0).unwrap().contains("jane@caterer.pl"));
+    }
+
+    #[test]
+    fn gives_the_turn_back_when_sending_fails() {
+        // Nothing listens on the port once the listener is gone
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let replies = RepliesFile::new("failed");
+        let mailer = mailer(port, &replies);
+        let auto_reply = AutoReply { subject: None, body: template("Thanks"), interval_secs: 3600 };
+        let message = Message::example();
+
+        assert!(auto_reply.reply(&mailer, &message, &context(&message)).is_err());
+        assert!(mailer.claim("jane@caterer.pl", 3600));
+    }
+
+    #[test]
+    fn forwards_mail() {
+        let (port, received) = sink();
+        let replies = RepliesFile::new("forward");
+        let mailer = mailer(port, &replies);
+        let mut message = Message::example();
+        message.body = Some("Soup and salad".to_string());
+        let mut forward = Forward {
+            to: vec!["Team <team@example.com>".to_string()],
+            mode: ForwardMode::Inline,
+            subject: None,
+            note: Some(template("From {from}")),
+        };
+
+        forward.send(&mailer, &message, &context(&message), None).unwrap();
+        let inline = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(inline.mail_from, "MAIL FROM:<bot@example.com>");
+        assert_eq!(inline.rcpt_to, ["RCPT TO:<team@example.com>"]);
+        assert!(inline.data.contains("Subject: Fwd: Menu\r\n"), "{}", inline.data);
+        assert!(inline.data.contains("Auto-Submitted: auto-forwarded\r\n"));
+        assert!(inline.data.contains("From jane@caterer.pl\r\n\r\n---------- Forwarded message ----------\r\n"));
+        assert!(inline.data.contains("Soup and salad"));
+
+        forward.mode = ForwardMode::Attachment;
+        forward.subject = Some(template("{subject} for {rule}"));
+        let raw = b"From: jane@caterer.pl\r\nSubject: Menu\r\n\r\nSoup and salad\r\n".to_vec();
+        forward.send(&mailer, &message, &context(&message), Some(raw)).unwrap();
+        let attached = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(attached.data.contains("Subject: Menu for menus\r\n"), "{}", attached.data);
+        assert!(attached.data.contains("Content-Type: message/rfc822\r\n"));
+        assert!(attached.data.contains("filename=\"Menu.eml\""));
+        assert!(attached.data.contains("\r\nSoup and salad\r\n"));
+    }
+}
@@ -0,0 +1,244 @@
+use serde::Deserialize;
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::Command;
+use log::trace;
+
+use crate::command;
+
+/// How notification sounds are played.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SoundBackend {
+    // Runs sox's "play"
+    Command,
+    // Plays in process, needs the native-audio feature
+    Native,
+    // Writes decoded samples to a WAV file instead of playing them, needs the
+    // audio-decoding feature; meant for tests
+    Null,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct SoundConfig {
+    pub backend: SoundBackend,
+    // Loudness, 1 plays the file as it is
+    pub volume: f32,
+    // Times the sound is played in a row
+    pub repeat: u32,
+    // File the null backend writes to, replaced on every sound
+    pub null_output: Option<String>,
+}
+
+impl Default for SoundConfig {
+    fn default() -> SoundConfig {
+        SoundConfig {
+            backend: if cfg!(feature = "native-audio") { SoundBackend::Native } else { SoundBackend::Command },
+            volume: 1.0,
+            repeat: 1,
+            null_output: None,
+        }
+    }
+}
+
+/// Problem with a volume setting, if any.
+pub fn check_volume(volume: f32) -> Option<String> {
+    (!volume.is_finite() || volume < 0.0).then(|| format!("volume {volume} has to be 0 or more"))
+}
+
+impl SoundConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        match self.backend {
+            SoundBackend::Native if !cfg!(feature = "native-audio") => {
+                problems.push("sound.backend native needs a build with the native-audio feature".to_string());
+            },
+            SoundBackend::Null if !cfg!(feature = "audio-decoding") => {
+                problems.push("sound.backend null needs a build with the audio-decoding feature".to_string());
+            },
+            SoundBackend::Null if self.null_output.is_none() => {
+                problems.push("sound.backend null needs sound.null_output".to_string());
+            },
+            _ => {},
+        }
+        if let Some(problem) = check_volume(self.volume) {
+            problems.push(format!("sound.{problem}"));
+        }
+        if self.repeat == 0 {
+            problems.push("sound.repeat has to be greater than 0".to_string());
+        }
+        problems
+    }
+}
+
+/// A sound ready to be played.
+#[derive(Debug, Clone)]
+pub struct Sound {
+    // Already expanded
+    pub file: PathBuf,
+    pub volume: f32,
+    pub repeat: u32,
+}
+
+/// Starts playing the sound. Fails right away when the file is missing or can't be
+/// decoded; trouble with the audio device is only logged, as playing goes on in the
+/// background.
+pub fn play(sound: Sound, config: &SoundConfig) -> Result<(), Box<dyn Error>> {
+    if !sound.file.is_file() {
+        return Err(format!("sound file {} doesn't exist", sound.file.display()).into());
+    }
+    trace!("Playing {} with {:?} backend", sound.file.display(), config.backend);
+
+    match config.backend {
+        SoundBackend::Command => {
+            let mut command = Command::new("play");
+            command.arg(&sound.file);
+            if sound.volume != 1.0 {
+                command.args(["vol", &sound.volume.to_string()]);
+            }
+            if sound.repeat > 1 {
+                command.args(["repeat", &(sound.repeat - 1).to_string()]);
+            }
+            command::spawn_background(command)?;
+        },
+        #[cfg(feature = "native-audio")]
+        SoundBackend::Native => {
+            let source = decoding::decode(&sound)?;
+            std::thread::spawn(move || {
+                if let Err(e) = decoding::play(source) {
+                    log::warn!("Failed to play {}: {e}", sound.file.display());
+                }
+            });
+        },
+        #[cfg(feature = "audio-decoding")]
+        SoundBackend::Null => {
+            let output = config.null_output.as_deref().ok_or("sound.null_output is not set")?;
+            decoding::write_wav(decoding::decode(&sound)?, output)?;
+        },
+        #[cfg(not(feature = "native-audio"))]
+        backend => return Err(format!("{backend:?} sound backend is not built in").into()),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "audio-decoding")]
+mod decoding {
+    use rodio::source::{self, Amplify, Buffered, FromIter};
+    use rodio::{Decoder, Source};
+    use std::error::Error;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::iter::RepeatN;
+
+    use super::Sound;
+
+    type Samples = FromIter<RepeatN<Buffered<Amplify<Decoder<BufReader<File>>>>>>;
+
+    /// Decodes the file, applying volume and repeats.
+    pub fn decode(sound: &Sound) -> Result<Samples, Box<dyn Error>> {
+        let decoded = Decoder::try_from(File::open(&sound.file)?)?.amplify(sound.volume).buffered();
+        Ok(source::from_iter(std::iter::repeat_n(decoded, sound.repeat as usize)))
+    }
+
+    pub fn write_wav(samples: Samples, output: &str) -> Result<(), Box<dyn Error>> {
+        rodio::wav_to_file(samples, crate::paths::expand_home(output))?;
+        Ok(())
+    }
+
+    /// Plays on the default output device, returns once done.
+    #[cfg(feature = "native-audio")]
+    pub fn play(samples: Samples) -> Result<(), Box<dyn Error>> {
+        let mut sink = rodio::DeviceSinkBuilder::open_default_sink()?;
+        sink.log_on_drop(false);
+        let player = rodio::Player::connect_new(sink.mixer());
+        player.append(samples);
+        player.sleep_until_end();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume_has_to_be_finite_and_not_negative() {
+        assert_eq!(check_volume(0.0), None);
+        assert_eq!(check_volume(2.5), None);
+        assert!(check_volume(-0.1).is_some());
+        assert!(check_volume(f32::NAN).is_some());
+        assert!(check_volume(f32::INFINITY).is_some());
+    }
+
+    #[test]
+    fn validates_defaults() {
+        assert!(SoundConfig::default().validate().is_empty());
+        let config = SoundConfig { volume: -1.0, repeat: 0, ..SoundConfig::default() };
+        assert_eq!(config.validate(), ["sound.volume -1 has to be 0 or more", "sound.repeat has to be greater than 0"]);
+    }
+
+    #[test]
+    fn null_backend_needs_output() {
+        let config = SoundConfig { backend: SoundBackend::Null, ..SoundConfig::default() };
+        assert_eq!(config.validate().len(), 1);
+        let config = SoundConfig { null_output: Some("out.wav".to_string()), ..config };
+        assert_eq!(config.validate().is_empty(), cfg!(feature = "audio-decoding"));
+    }
+
+    #[test]
+    fn missing_file_fails_right_away() {
+        let sound = Sound { file: PathBuf::from("/nonexistent/beep.wav"), volume: 1.0, repeat: 1 };
+        assert!(play(sound, &SoundConfig::default()).is_err());
+    }
+
+    // Mono 16 bit PCM WAV with the given samples
+    #[cfg(feature = "audio-decoding")]
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let data_length = samples.len() as u32 * 2;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_length).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_length.to_le_bytes());
+        wav.extend(samples.iter().flat_map(|sample| sample.to_le_bytes()));
+        wav
+    }
+
+    #[cfg(feature = "audio-decoding")]
+    #[test]
+    fn null_backend_applies_volume_and_repeats() {
+        use rodio::{Decoder, Source};
+
+        let directory = std::env::temp_dir().join(format!("imap-listener-sound-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let input = directory.join("beep.wav");
+        let output = directory.join("out.wav");
+        std::fs::write(&input, wav(&[16384, -16384, 8192, 0])).unwrap();
+
+        let config = SoundConfig {
+            backend: SoundBackend::Null,
+            null_output: Some(output.display().to_string()),
+            ..SoundConfig::default()
+        };
+        play(Sound { file: input, volume: 0.5, repeat: 2 }, &config).unwrap();
+
+        let written = Decoder::try_from(std::fs::File::open(&output).unwrap()).unwrap();
+        assert_eq!(written.channels().get(), 1);
+        let samples: Vec<f32> = written.collect();
+        assert_eq!(samples.len(), 8);
+        let expected = [0.25, -0.25, 0.125, 0.0];
+        for (sample, expected) in samples.iter().zip(expected.iter().cycle()) {
+            assert!((sample - expected).abs() < 0.001, "{samples:?}");
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
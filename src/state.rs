@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use log::info;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct MailboxState {
+    uid_validity: u32,
+    // UIDs of mails that rules were already run on
+    processed: BTreeSet<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StoredState {
+    // By account name, then by mailbox name
+    accounts: BTreeMap<String, BTreeMap<String, MailboxState>>,
+}
+
+impl StoredState {
+    fn mailbox(&self, account: &str, mailbox: &str) -> Option<&MailboxState> {
+        self.accounts.get(account).and_then(|mailboxes| mailboxes.get(mailbox))
+    }
+
+    fn mailbox_mut(&mut self, account: &str, mailbox: &str) -> &mut MailboxState {
+        self.accounts.entry(account.to_string()).or_default().entry(mailbox.to_string()).or_default()
+    }
+}
+
+/// Remembers which mails were already handled, so that restarts and reconnects
+/// don't run actions on them again. Shared by all workers.
+pub struct StateStore {
+    path: PathBuf,
+    state: Mutex<StoredState>,
+}
+
+impl StateStore {
+    pub fn open(path: &str) -> Result<StateStore, Box<dyn Error>> {
+        let state = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StoredState::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(StateStore { path: PathBuf::from(path), state: Mutex::new(state) })
+    }
+
+    /// Forgets everything about the mailbox if its UIDVALIDITY changed, since
+    /// the remembered UIDs now refer to different mails.
+    pub fn check_uid_validity(&self, account: &str, mailbox: &str, uid_validity: u32) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        let mailbox_state = state.mailbox_mut(account, mailbox);
+        if mailbox_state.uid_validity == uid_validity {
+            return Ok(());
+        }
+
+        if !mailbox_state.processed.is_empty() {
+            info!("UIDVALIDITY changed from {} to {uid_validity}, forgetting processed mails", mailbox_state.uid_validity);
+        }
+        mailbox_state.uid_validity = uid_validity;
+        mailbox_state.processed.clear();
+        self.save(&state)
+    }
+
+    pub fn is_processed(&self, account: &str, mailbox: &str, uid: u32) -> bool {
+        let state = self.state.lock().unwrap();
+        state.mailbox(account, mailbox).is_some_and(|mailbox_state| mailbox_state.processed.contains(&uid))
+    }
+
+    pub fn mark_processed(&self, account: &str, mailbox: &str, uid: u32) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        state.mailbox_mut(account, mailbox).processed.insert(uid);
+        self.save(&state)
+    }
+
+    /// UIDs of the mailbox that rules were already run on, in ascending order.
+    pub fn processed(&self, account: &str, mailbox: &str) -> Vec<u32> {
+        let state = self.state.lock().unwrap();
+        state.mailbox(account, mailbox)
+            .map(|mailbox_state| mailbox_state.processed.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Drops remembered UIDs of mails that are gone from the mailbox. Mails that are
+    /// still there stay remembered even when seen, in case they are marked unseen again.
+    pub fn retain(&self, account: &str, mailbox: &str, existing: &HashSet<u32>) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        let Some(mailbox_state) = state.accounts.get_mut(account).and_then(|mailboxes| mailboxes.get_mut(mailbox)) else {
+            return Ok(());
+        };
+        let before = mailbox_state.processed.len();
+        mailbox_state.processed.retain(|uid| existing.contains(uid));
+        if mailbox_state.processed.len() != before {
+            self.save(&state)?;
+        }
+        Ok(())
+    }
+
+    fn save(&self, state: &StoredState) -> Result<(), Box<dyn Error>> {
+        // Written next to the target and renamed, so a crash never leaves a truncated file
+        let temporary_path = self.path.with_extension("tmp");
+        fs::write(&temporary_path, serde_json::to_string_pretty(state)?)?;
+        fs::rename(&temporary_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TempFile;
+
+    #[test]
+    fn remembers_processed_mails_across_restarts() {
+        let file = TempFile::new("state-restart");
+        let state = StateStore::open(&file.path()).unwrap();
+        state.check_uid_validity("work", "INBOX", 7).unwrap();
+        state.mark_processed("work", "INBOX", 42).unwrap();
+        assert!(state.is_processed("work", "INBOX", 42));
+        assert!(!state.is_processed("work", "INBOX", 43));
+        assert!(!state.is_processed("home", "INBOX", 42));
+
+        let state = StateStore::open(&file.path()).unwrap();
+        state.check_uid_validity("work", "INBOX", 7).unwrap();
+        assert!(state.is_processed("work", "INBOX", 42));
+    }
+
+    #[test]
+    fn forgets_mails_when_uid_validity_changes() {
+        let file = TempFile::new("state-validity");
+        let state = StateStore::open(&file.path()).unwrap();
+        state.check_uid_validity("work", "INBOX", 7).unwrap();
+        state.mark_processed("work", "INBOX", 42).unwrap();
+        state.mark_processed("work", "Orders", 42).unwrap();
+
+        state.check_uid_validity("work", "INBOX", 8).unwrap();
+        assert!(!state.is_processed("work", "INBOX", 42));
+        assert!(state.is_processed("work", "Orders", 42));
+        assert!(!StateStore::open(&file.path()).unwrap().is_processed("work", "INBOX", 42));
+    }
+
+    #[test]
+    fn forgets_only_mails_that_are_gone() {
+        let file = TempFile::new("state-retain");
+        let state = StateStore::open(&file.path()).unwrap();
+        for uid in [3, 4, 5] {
+            state.mark_processed("work", "INBOX", uid).unwrap();
+        }
+        assert_eq!(state.processed("work", "INBOX"), [3, 4, 5]);
+
+        state.retain("work", "INBOX", &HashSet::from([3, 5, 9])).unwrap();
+        assert_eq!(state.processed("work", "INBOX"), [3, 5]);
+        assert_eq!(StateStore::open(&file.path()).unwrap().processed("work", "INBOX"), [3, 5]);
+    }
+
+    #[test]
+    fn keeps_mailboxes_of_accounts_apart() {
+        let file = TempFile::new("state-names");
+        let state = StateStore::open(&file.path()).unwrap();
+        state.mark_processed("a/b", "c", 1).unwrap();
+        assert!(!state.is_processed("a", "b/c", 1));
+        state.mark_processed("a", "b/c", 2).unwrap();
+
+        let state = StateStore::open(&file.path()).unwrap();
+        assert_eq!(state.processed("a/b", "c"), [1]);
+        assert_eq!(state.processed("a", "b/c"), [2]);
+    }
+}
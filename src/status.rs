@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use log::warn;
+
+use crate::backoff::CircuitState;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkerStatus {
+    pub connected: bool,
+    pub circuit: CircuitState,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub next_attempt: Option<chrono::DateTime<chrono::Utc>>,
+    pub updated: chrono::DateTime<chrono::Utc>,
+}
+
+impl Default for WorkerStatus {
+    fn default() -> WorkerStatus {
+        WorkerStatus {
+            connected: false,
+            circuit: CircuitState::Closed,
+            consecutive_failures: 0,
+            last_error: None,
+            next_attempt: None,
+            updated: chrono::offset::Utc::now(),
+        }
+    }
+}
+
+/// Connection state of every worker, mirrored to the status file so that
+/// `imap-listener status` can show it.
+pub struct StatusBoard {
+    path: PathBuf,
+    // Keyed by worker name, "<account>/<mailbox>"
+    workers: Mutex<BTreeMap<String, WorkerStatus>>,
+}
+
+impl StatusBoard {
+    pub fn new(path: &str) -> StatusBoard {
+        StatusBoard { path: PathBuf::from(path), workers: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn update(&self, worker: &str, change: impl FnOnce(&mut WorkerStatus)) {
+        let mut workers = self.workers.lock().unwrap();
+        let status = workers.entry(worker.to_string()).or_default();
+        change(status);
+        status.updated = chrono::offset::Utc::now();
+
+        if let Err(e) = self.save(&workers) {
+            warn!("Failed to write status file: {e}");
+        }
+    }
+
+    fn save(&self, workers: &BTreeMap<String, WorkerStatus>) -> Result<(), Box<dyn Error>> {
+        let temporary_path = self.path.with_extension("tmp");
+        fs::write(&temporary_path, serde_json::to_string_pretty(workers)?)?;
+        fs::rename(&temporary_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Reads the status file written by a running listener.
+pub fn read_status(path: &str) -> Result<BTreeMap<String, WorkerStatus>, Box<dyn Error>> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TempFile;
+
+    #[test]
+    fn status_file_round_trips() {
+        let file = TempFile::new("status");
+        let board = StatusBoard::new(&file.path());
+        board.update("work/INBOX", |worker| worker.connected = true);
+        board.update("home/INBOX", |worker| {
+            worker.circuit = CircuitState::Open;
+            worker.consecutive_failures = 5;
+            worker.last_error = Some("connection refused".to_string());
+        });
+        board.update("work/INBOX", |worker| worker.consecutive_failures = 1);
+
+        let workers = read_status(&file.path()).unwrap();
+        assert_eq!(workers.keys().collect::<Vec<_>>(), ["home/INBOX", "work/INBOX"]);
+        let work = &workers["work/INBOX"];
+        assert!(work.connected);
+        assert_eq!(work.consecutive_failures, 1);
+        let home = &workers["home/INBOX"];
+        assert!(!home.connected);
+        assert_eq!(home.circuit, CircuitState::Open);
+        assert_eq!(home.last_error.as_deref(), Some("connection refused"));
+        assert!(home.updated <= work.updated);
+    }
+
+    #[test]
+    fn missing_status_file_is_an_error() {
+        assert!(read_status(&TempFile::new("status-missing").path()).is_err());
+    }
+}
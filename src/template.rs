@@ -0,0 +1,180 @@
+use serde::Deserialize;
+
+use crate::rules::Message;
+
+/// Names that can appear in "{...}" placeholders.
+pub const VARIABLES: &[&str] = &[
+    "account", "mailbox", "rule", "uid", "from", "from_name", "from_domain", "subject", "date", "year", "month", "day",
+];
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Placeholder(String),
+}
+
+/// Text with "{name}" placeholders filled in from the mail being handled; "{{" and "}}"
+/// stand for literal braces. Unknown names are rejected when rules are loaded.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        return Err(format!("unclosed {{ in \"{text}\""));
+                    }
+                    if !VARIABLES.contains(&name.as_str()) {
+                        return Err(format!("unknown placeholder {{{name}}} in \"{text}\", known are {}", VARIABLES.join(", ")));
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(name));
+                },
+                '}' => return Err(format!("unmatched }} in \"{text}\"")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Template { parts })
+    }
+}
+
+impl Template {
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    /// Text before the first placeholder.
+    pub fn literal_prefix(&self) -> &str {
+        match self.parts.first() {
+            Some(Part::Literal(text)) => text,
+            _ => "",
+        }
+    }
+
+    /// Fills in the placeholders, passing every value through `escape` first.
+    pub fn render(&self, context: &Context<'_>, escape: impl Fn(String) -> String) -> String {
+        self.parts.iter().map(|part| match part {
+            Part::Literal(text) => text.clone(),
+            Part::Placeholder(name) => escape(context.value(name)),
+        }).collect()
+    }
+}
+
+/// Where the values of placeholders come from.
+pub struct Context<'a> {
+    pub account: &'a str,
+    pub mailbox: &'a str,
+    pub rule: &'a str,
+    pub message: &'a Message,
+}
+
+impl Context<'_> {
+    pub fn value(&self, name: &str) -> String {
+        let from = self.message.from.first();
+        match name {
+            "account" => self.account.to_string(),
+            "mailbox" => self.mailbox.to_string(),
+            "rule" => self.rule.to_string(),
+            "uid" => self.message.uid.to_string(),
+            "from" => from.and_then(|from| from.address()).unwrap_or_default(),
+            "from_name" => from.and_then(|from| from.name.clone()).unwrap_or_default(),
+            "from_domain" => from.and_then(|from| from.host.clone()).unwrap_or_default().to_lowercase(),
+            "subject" => self.message.subject.clone(),
+            "date" => self.message.date.format("%Y-%m-%d").to_string(),
+            "year" => self.message.date.format("%Y").to_string(),
+            "month" => self.message.date.format("%m").to_string(),
+            "day" => self.message.date.format("%d").to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> Message {
+        let mut message = Message::example();
+        message.from[0].host = Some("Caterer.PL".to_string());
+        message.subject = "Menu {today}".to_string();
+        message
+    }
+
+    fn render(text: &str) -> String {
+        let message = message();
+        let context = Context { account: "work", mailbox: "INBOX", rule: "menus", message: &message };
+        Template::try_from(text.to_string()).unwrap().render(&context, |value| value)
+    }
+
+    #[test]
+    fn fills_in_placeholders() {
+        assert_eq!(render("{from_name} <{from}> from {from_domain}"), "Jane Doe <jane@Caterer.PL> from caterer.pl");
+        assert_eq!(render("{account}/{mailbox}/{rule}/{uid}"), "work/INBOX/menus/42");
+        assert_eq!(render("{year}-{month}-{day} = {date}"), "2024-03-07 = 2024-03-07");
+        // Braces in values are not placeholders
+        assert_eq!(render("{subject}"), "Menu {today}");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(render("{{\"uid\": {uid}}}"), "{\"uid\": 42}");
+    }
+
+    #[test]
+    fn escapes_values_only() {
+        let message = message();
+        let context = Context { account: "work", mailbox: "INBOX", rule: "menus", message: &message };
+        let template = Template::try_from("<b>{from_name}</b>".to_string()).unwrap();
+        assert_eq!(template.render(&context, |value| value.replace(' ', "_")), "<b>Jane_Doe</b>");
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        let error = |text: &str| Template::try_from(text.to_string()).unwrap_err();
+        assert!(error("{sender}").contains("unknown placeholder {sender}"));
+        assert!(error("{subject").contains("unclosed {"));
+        assert!(error("subject}").contains("unmatched }"));
+    }
+
+    #[test]
+    fn literal_prefix_ends_at_first_placeholder() {
+        let template = Template::try_from("https://example.com/{uid}/x".to_string()).unwrap();
+        assert_eq!(template.literal_prefix(), "https://example.com/");
+        assert_eq!(Template::try_from("{uid}".to_string()).unwrap().literal_prefix(), "");
+        assert!(Template::try_from(String::new()).unwrap().is_empty());
+    }
+}
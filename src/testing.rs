@@ -0,0 +1,117 @@
+use std::cell::RefCell;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+
+/// Path of a file of the test's own in the temp directory, removed again when dropped.
+pub struct TempFile(pub PathBuf);
+
+impl TempFile {
+    pub fn new(name: &str) -> TempFile {
+        let path = std::env::temp_dir().join(format!("imap-listener-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        TempFile(path)
+    }
+
+    pub fn path(&self) -> String {
+        self.0.display().to_string()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Commands an IMAP client wrote to a [`MockStream`].
+#[derive(Debug, Clone, Default)]
+pub struct Sent(Rc<RefCell<Vec<u8>>>);
+
+impl Sent {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).to_string()
+    }
+}
+
+/// IMAP connection that replays canned server responses and keeps what the client sent.
+#[derive(Debug)]
+pub struct MockStream {
+    responses: io::Cursor<Vec<u8>>,
+    sent: Sent,
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.responses.read(buf)
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Client that gets the given responses, greeting already read; its commands are tagged a1, a2...
+pub fn imap_client(responses: &str) -> (imap::Client<MockStream>, Sent) {
+    let sent = Sent::default();
+    let stream = MockStream { responses: io::Cursor::new(responses.as_bytes().to_vec()), sent: sent.clone() };
+    (imap::Client::new(stream), sent)
+}
+
+/// Session that is logged in already; its commands are tagged from a2 on.
+pub fn imap_session(responses: &str) -> (imap::Session<MockStream>, Sent) {
+    let (client, sent) = imap_client(&format!("a1 OK Logged in\r\n{responses}"));
+    let session = client.login("jane", "secret").map_err(|(e, _)| e).unwrap();
+    sent.0.borrow_mut().clear();
+    (session, sent)
+}
+
+/// HTTP server answering each request with the next status and JSON body, and handing
+/// the requests it got to the test.
+pub fn http_server(responses: &[(u16, &str)]) -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    let responses: Vec<(u16, String)> = responses.iter().map(|(status, body)| (*status, body.to_string())).collect();
+    thread::spawn(move || {
+        for (status, body) in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("Content-Length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut request_body = vec![0; content_length];
+            reader.read_exact(&mut request_body).unwrap();
+            request.push_str(&String::from_utf8(request_body).unwrap());
+            sender.send(request).unwrap();
+            let response = format!(
+                "HTTP/1.1 {status} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len(),
+            );
+            (&stream).write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (url, receiver)
+}
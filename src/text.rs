@@ -0,0 +1,336 @@
+use regex::Regex;
+use serde::Deserialize;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Lowercases, drops diacritics and collapses whitespace, so that "Kanapkí " and
+/// "kanapki", or "Łódź" and "lodz", compare equal.
+pub fn normalize(text: &str) -> String {
+    let lowercase = text.nfkd().filter(|c| !is_combining_mark(*c)).collect::<String>().to_lowercase();
+    let mut folded = String::with_capacity(lowercase.len());
+    for c in lowercase.chars() {
+        match fold_letter(c) {
+            Some(replacement) => folded.push_str(replacement),
+            None => folded.push(c),
+        }
+    }
+    folded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Lowercase letters with a stroke or ligature, which NFKD doesn't split into a base
+// letter and a mark, spelled the way they are written without diacritics
+fn fold_letter(c: char) -> Option<&'static str> {
+    let replacement = match c {
+        'ł' => "l",
+        'ø' => "o",
+        'đ' | 'ð' => "d",
+        'ħ' => "h",
+        'ı' => "i",
+        'ŧ' => "t",
+        'ß' => "ss",
+        'æ' => "ae",
+        'œ' => "oe",
+        'þ' => "th",
+        _ => return None,
+    };
+    Some(replacement)
+}
+
+// Turns "*" and "?" wildcards into an anchored regex
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Case-insensitive "*" and "?" wildcard pattern, for file names and the like.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub struct GlobPattern(Regex);
+
+impl TryFrom<String> for GlobPattern {
+    type Error = String;
+
+    fn try_from(glob: String) -> Result<GlobPattern, String> {
+        Regex::new(&format!("(?i){}", glob_to_regex(&glob)))
+            .map(GlobPattern)
+            .map_err(|e| format!("invalid glob {glob}: {e}"))
+    }
+}
+
+impl GlobPattern {
+    pub fn matches(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "serde_json::Value")]
+enum PatternSpec {
+    // Plain string, fuzzy match within edit distance 2 as before modes existed
+    Plain(String),
+    Mode(ModeSpec),
+}
+
+// Taken apart by hand rather than with an untagged enum, so that a misspelled setting
+// gets a better error than "data did not match any variant"
+impl TryFrom<serde_json::Value> for PatternSpec {
+    type Error = String;
+
+    fn try_from(value: serde_json::Value) -> Result<PatternSpec, String> {
+        match value {
+            serde_json::Value::String(text) => Ok(PatternSpec::Plain(text)),
+            value @ serde_json::Value::Object(_) => {
+                serde_json::from_value(value).map(PatternSpec::Mode).map_err(|e| format!("invalid pattern: {e}"))
+            },
+            value => Err(format!("pattern has to be a string or a table, not {value}")),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct ModeSpec {
+    // Exactly one of these picks the mode
+    exact: Option<String>,
+    contains: Option<String>,
+    keyword: Option<String>,
+    glob: Option<String>,
+    regex: Option<String>,
+    fuzzy: Option<String>,
+    // Fuzzy only: most edits allowed, or least similarity between 0 and 1
+    max_distance: Option<usize>,
+    min_ratio: Option<f64>,
+    // Compare normalized text, on by default except for exact and regex
+    normalize: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    // Whole text is equal
+    Exact(String),
+    // Text contains the pattern anywhere
+    Contains(String),
+    // Pattern appears as whole words
+    Keyword(Regex),
+    // Whole text matches "*" and "?" wildcards
+    Glob(Regex),
+    Regex(Regex),
+    // Whole text is close to the pattern
+    Fuzzy { text: String, threshold: Threshold },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Threshold {
+    // Most edits allowed
+    Distance(usize),
+    // Least share of characters that stay the same
+    Ratio(f64),
+}
+
+/// Pattern of a subject (or other text) condition.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "PatternSpec")]
+pub struct TextPattern {
+    matcher: Matcher,
+    normalize: bool,
+}
+
+impl TryFrom<PatternSpec> for TextPattern {
+    type Error = String;
+
+    fn try_from(spec: PatternSpec) -> Result<TextPattern, String> {
+        let spec = match spec {
+            PatternSpec::Plain(text) => return Ok(TextPattern::fuzzy(&text)),
+            PatternSpec::Mode(spec) => spec,
+        };
+
+        let modes = [&spec.exact, &spec.contains, &spec.keyword, &spec.glob, &spec.regex, &spec.fuzzy];
+        if modes.iter().filter(|mode| mode.is_some()).count() != 1 {
+            return Err("pattern needs exactly one of exact, contains, keyword, glob, regex or fuzzy".to_string());
+        }
+        if spec.fuzzy.is_none() && (spec.max_distance.is_some() || spec.min_ratio.is_some()) {
+            return Err("max_distance and min_ratio only apply to fuzzy patterns".to_string());
+        }
+        if spec.max_distance.is_some() && spec.min_ratio.is_some() {
+            return Err("fuzzy pattern can't have both max_distance and min_ratio".to_string());
+        }
+        if spec.min_ratio.is_some_and(|ratio| !(0.0..=1.0).contains(&ratio)) {
+            return Err("min_ratio has to be between 0 and 1".to_string());
+        }
+
+        let normalize_by_default = spec.exact.is_none() && spec.regex.is_none();
+        let normalized = spec.normalize.unwrap_or(normalize_by_default);
+        let prepare = |text: &str| if normalized { normalize(text) } else { text.to_string() };
+        let compile = |regex: &str| Regex::new(regex).map_err(|e| format!("invalid pattern regex {regex}: {e}"));
+
+        let matcher = if let Some(text) = &spec.exact {
+            Matcher::Exact(prepare(text))
+        } else if let Some(text) = &spec.contains {
+            Matcher::Contains(prepare(text))
+        } else if let Some(text) = &spec.keyword {
+            Matcher::Keyword(compile(&format!(r"\b{}\b", regex::escape(&prepare(text))))?)
+        } else if let Some(glob) = &spec.glob {
+            Matcher::Glob(compile(&glob_to_regex(&prepare(glob)))?)
+        } else if let Some(regex) = &spec.regex {
+            Matcher::Regex(compile(regex)?)
+        } else if let Some(text) = &spec.fuzzy {
+            let threshold = match spec.min_ratio {
+                Some(ratio) => Threshold::Ratio(ratio),
+                None => Threshold::Distance(spec.max_distance.unwrap_or(2)),
+            };
+            Matcher::Fuzzy { text: prepare(text), threshold }
+        } else {
+            unreachable!("exactly one mode is set");
+        };
+
+        Ok(TextPattern { matcher, normalize: normalized })
+    }
+}
+
+/// Like a text pattern, but a plain string is searched for anywhere in the text, as
+/// befits long texts like mail bodies.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "PatternSpec")]
+pub struct SearchPattern(TextPattern);
+
+impl TryFrom<PatternSpec> for SearchPattern {
+    type Error = String;
+
+    fn try_from(spec: PatternSpec) -> Result<SearchPattern, String> {
+        match spec {
+            PatternSpec::Plain(text) => Ok(SearchPattern(TextPattern {
+                matcher: Matcher::Contains(normalize(&text)),
+                normalize: true,
+            })),
+            spec => TextPattern::try_from(spec).map(SearchPattern),
+        }
+    }
+}
+
+impl SearchPattern {
+    pub fn matches(&self, text: &str) -> bool {
+        self.0.matches(text)
+    }
+}
+
+impl TextPattern {
+    /// Normalized text within edit distance 2, what plain strings mean.
+    pub fn fuzzy(text: &str) -> TextPattern {
+        TextPattern {
+            matcher: Matcher::Fuzzy { text: normalize(text), threshold: Threshold::Distance(2) },
+            normalize: true,
+        }
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        let text = if self.normalize { normalize(text) } else { text.to_string() };
+
+        match &self.matcher {
+            Matcher::Exact(pattern) => text == *pattern,
+            Matcher::Contains(pattern) => text.contains(pattern.as_str()),
+            Matcher::Keyword(regex) | Matcher::Glob(regex) | Matcher::Regex(regex) => regex.is_match(&text),
+            Matcher::Fuzzy { text: pattern, threshold } => {
+                let distance = edit_distance::edit_distance(&text, pattern);
+                match threshold {
+                    Threshold::Distance(max_distance) => distance <= *max_distance,
+                    Threshold::Ratio(min_ratio) => {
+                        let longest = text.chars().count().max(pattern.chars().count()).max(1);
+                        1.0 - distance as f64 / longest as f64 >= *min_ratio
+                    },
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(json: &str) -> Result<TextPattern, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn normalizes_case_diacritics_and_whitespace() {
+        assert_eq!(normalize("  Kanapkí \t z  SZYNKĄ "), "kanapki z szynka");
+        assert_eq!(normalize("ﬁle"), "file");
+    }
+
+    #[test]
+    fn folds_letters_without_decomposition() {
+        assert_eq!(normalize("Łódź"), "lodz");
+        assert_eq!(normalize("Ørsted Đaković"), "orsted dakovic");
+        assert_eq!(normalize("Straße Æsir"), "strasse aesir");
+        assert!(pattern(r#"{"exact": "lodz", "normalize": true}"#).unwrap().matches("ŁÓDŹ"));
+    }
+
+    #[test]
+    fn globs_match_whole_name_case_insensitively() {
+        let glob = GlobPattern::try_from("menu-*.PDF".to_string()).unwrap();
+        assert!(glob.matches("Menu-week 12.pdf"));
+        assert!(!glob.matches("old-menu-1.pdf"));
+        let glob = GlobPattern::try_from("?.txt".to_string()).unwrap();
+        assert!(glob.matches("a.txt"));
+        assert!(!glob.matches("ab.txt"));
+        // Regex characters in the glob are literal
+        assert!(!GlobPattern::try_from("a.b".to_string()).unwrap().matches("axb"));
+    }
+
+    #[test]
+    fn plain_string_is_fuzzy() {
+        let plain = pattern(r#""Catering""#).unwrap();
+        assert!(plain.matches("catrng"));
+        assert!(!plain.matches("cat"));
+    }
+
+    #[test]
+    fn matches_each_mode() {
+        assert!(pattern(r#"{"exact": "Lunch"}"#).unwrap().matches("Lunch"));
+        assert!(!pattern(r#"{"exact": "Lunch"}"#).unwrap().matches("lunch"));
+        assert!(pattern(r#"{"contains": "sushi"}"#).unwrap().matches("Today: SUSHI and rolls"));
+        assert!(pattern(r#"{"keyword": "sushi"}"#).unwrap().matches("sushi, rolls"));
+        assert!(!pattern(r#"{"keyword": "sushi"}"#).unwrap().matches("sushibar"));
+        assert!(pattern(r#"{"glob": "Menu *"}"#).unwrap().matches("menu na środę"));
+        assert!(pattern(r#"{"regex": "^\\[JIRA-\\d+\\]"}"#).unwrap().matches("[JIRA-12] Build"));
+        assert!(pattern(r#"{"fuzzy": "sandwiches", "min_ratio": 0.8}"#).unwrap().matches("sandwitches"));
+        assert!(!pattern(r#"{"fuzzy": "sandwiches", "max_distance": 0}"#).unwrap().matches("sandwitches"));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(pattern(r#"{"exact": "a", "contains": "b"}"#).is_err());
+        assert!(pattern(r#"{"exact": "a", "min_ratio": 0.5}"#).is_err());
+        assert!(pattern(r#"{"fuzzy": "a", "min_ratio": 0.5, "max_distance": 1}"#).is_err());
+        assert!(pattern(r#"{"fuzzy": "a", "min_ratio": 1.5}"#).is_err());
+        assert!(pattern(r#"{"regex": "("}"#).is_err());
+    }
+
+    #[test]
+    fn names_misspelled_settings() {
+        let error = pattern(r#"{"exatc": "Lunch"}"#).unwrap_err();
+        assert!(error.contains("invalid pattern: unknown field `exatc`"), "{error}");
+        let error = pattern(r#"{"fuzzy": "Lunch", "max_distnace": 1}"#).unwrap_err();
+        assert!(error.contains("unknown field `max_distnace`"), "{error}");
+        let error = pattern("3").unwrap_err();
+        assert!(error.contains("pattern has to be a string or a table, not 3"), "{error}");
+
+        let toml = r#"subject = ["menu", { keywrod = "sushi" }]"#;
+        let error = toml::from_str::<std::collections::HashMap<String, Vec<TextPattern>>>(toml).unwrap_err();
+        assert!(error.to_string().contains("unknown field `keywrod`"), "{error}");
+    }
+
+    #[test]
+    fn search_pattern_plain_string_is_contains() {
+        let search: SearchPattern = serde_json::from_str(r#""Zamówienie""#).unwrap();
+        assert!(search.matches("Twoje zamowienie zostalo wyslane"));
+    }
+}
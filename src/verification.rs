@@ -0,0 +1,291 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::address::Address;
+use crate::dkim;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct VerificationConfig {
+    // The topmost Authentication-Results header is trusted when added by one of these
+    // servers; anyone can put such headers in a mail they send
+    pub trusted_authserv_ids: Vec<String>,
+    // Trust the topmost Received-SPF header, added by the server that received the mail
+    pub trust_received_spf: bool,
+    // Keys to check DKIM signatures locally instead of trusting the server, mostly for
+    // testing. Keyed by "<selector>._domainkey.<domain>", value is the TXT record
+    pub dkim_keys: HashMap<String, String>,
+}
+
+impl VerificationConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.trusted_authserv_ids.iter().any(|id| id.is_empty()) {
+            problems.push("verification.trusted_authserv_ids can't contain an empty id".to_string());
+        }
+        for (name, record) in &self.dkim_keys {
+            if let Err(e) = dkim::validate_key(record) {
+                problems.push(format!("verification.dkim_keys: key {name} can't be used: {e}"));
+            }
+        }
+        problems
+    }
+
+    /// Whether anything at all can vouch for a sender.
+    pub fn is_configured(&self) -> bool {
+        !self.trusted_authserv_ids.is_empty() || self.trust_received_spf || !self.dkim_keys.is_empty()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum VerificationMethod {
+    Dkim,
+    Spf,
+}
+
+/// What trusted parties confirmed about where a mail comes from.
+#[derive(Debug, Clone, Default)]
+pub struct Authentication {
+    // Domains of passing DKIM signatures
+    pub dkim_domains: Vec<String>,
+    // Envelope sender domains SPF passed for
+    pub spf_domains: Vec<String>,
+}
+
+impl Authentication {
+    /// Whether the method passed for the domain of the From address. Mails with
+    /// several From addresses are never verified.
+    pub fn verifies(&self, method: VerificationMethod, from: &[Address]) -> bool {
+        let [Address { host: Some(from_domain), .. }] = from else {
+            return false;
+        };
+        let domains = match method {
+            VerificationMethod::Dkim => &self.dkim_domains,
+            VerificationMethod::Spf => &self.spf_domains,
+        };
+        domains.iter().any(|domain| aligned(&from_domain.to_lowercase(), domain))
+    }
+}
+
+// From domain is the authenticated domain or one of its subdomains
+fn aligned(from_domain: &str, domain: &str) -> bool {
+    from_domain == domain || from_domain.ends_with(&format!(".{domain}"))
+}
+
+// Removes RFC 5322 comments, which may nest
+fn strip_comments(value: &str) -> String {
+    let mut stripped = String::with_capacity(value.len());
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' && depth == 0 {
+            quoted = !quoted;
+        } else if c == '(' && !quoted {
+            depth += 1;
+            continue;
+        } else if c == ')' && !quoted && depth > 0 {
+            depth -= 1;
+            continue;
+        }
+        if depth == 0 {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+// Domain part of "user@domain", "@domain" or "domain"
+fn domain_of(value: &str) -> String {
+    let value = value.trim_matches('"');
+    value.rsplit('@').next().unwrap_or(value).to_lowercase()
+}
+
+// RFC 8601: "authserv-id [version]; method=result ptype.property=value ...; ..."
+fn parse_authentication_results(value: &str, trusted_ids: &[String], authentication: &mut Authentication) {
+    let value = strip_comments(value);
+    let mut statements = value.split(';');
+    let authserv_id = statements.next().and_then(|id| id.split_whitespace().next()).unwrap_or_default();
+    if !trusted_ids.iter().any(|trusted| trusted.eq_ignore_ascii_case(authserv_id)) {
+        return;
+    }
+
+    for statement in statements {
+        let mut tokens = statement.split_whitespace();
+        let Some((method, result)) = tokens.next().and_then(|token| token.split_once('=')) else {
+            continue;
+        };
+        if !result.eq_ignore_ascii_case("pass") {
+            continue;
+        }
+        let properties: HashMap<String, &str> = tokens
+            .filter_map(|token| token.split_once('='))
+            .map(|(property, value)| (property.to_lowercase(), value))
+            .collect();
+
+        match method.to_lowercase().as_str() {
+            "dkim" => {
+                if let Some(domain) = properties.get("header.d").or_else(|| properties.get("header.i")) {
+                    authentication.dkim_domains.push(domain_of(domain));
+                }
+            },
+            "spf" => {
+                if let Some(mail_from) = properties.get("smtp.mailfrom") {
+                    authentication.spf_domains.push(domain_of(mail_from));
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+// RFC 7208: "result (comment) key=value; key=value"
+fn parse_received_spf(value: &str, authentication: &mut Authentication) {
+    let value = strip_comments(value);
+    let mut parts = value.splitn(2, char::is_whitespace);
+    if !parts.next().is_some_and(|result| result.eq_ignore_ascii_case("pass")) {
+        return;
+    }
+    let envelope_from = parts.next().unwrap_or_default().split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("envelope-from"));
+    if let Some((_, envelope_from)) = envelope_from {
+        authentication.spf_domains.push(domain_of(envelope_from.trim()));
+    }
+}
+
+/// Collects what trusted headers, and local DKIM checks when keys are configured, say about a mail.
+pub fn authenticate(headers: &[u8], body: Option<&[u8]>, config: &VerificationConfig) -> Authentication {
+    let mut authentication = Authentication::default();
+    let fields = dkim::header_fields(headers);
+    // Only the topmost one of a kind was added by our server, the rest came with the mail
+    let topmost = |name: &[u8]| fields.iter().find(|field| dkim::field_name(field).eq_ignore_ascii_case(name));
+
+    // A signature or result may cover a different From than the one the server reports
+    // (RFC 6376, section 8.15), so such mails are never verified
+    if fields.iter().filter(|field| dkim::field_name(field).eq_ignore_ascii_case(b"From")).count() > 1 {
+        return authentication;
+    }
+
+    if let Some(field) = topmost(b"Authentication-Results") {
+        parse_authentication_results(&dkim::field_value(field), &config.trusted_authserv_ids, &mut authentication);
+    }
+
+    if let (true, Some(field)) = (config.trust_received_spf, topmost(b"Received-SPF")) {
+        parse_received_spf(&dkim::field_value(field), &mut authentication);
+    }
+
+    if let (false, Some(body)) = (config.dkim_keys.is_empty(), body) {
+        authentication.dkim_domains.extend(dkim::verify(headers, body, &config.dkim_keys));
+    }
+
+    authentication
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> Vec<String> {
+        vec!["mx.example.com".to_string()]
+    }
+
+    #[test]
+    fn reads_passing_results_of_trusted_server() {
+        let mut authentication = Authentication::default();
+        parse_authentication_results(
+            "MX.example.com (version 1); dkim=pass (2048-bit key) header.d=Caterer.pl header.s=menu; \
+             spf=pass smtp.mailfrom=bounces@mail.caterer.pl; dmarc=pass header.from=caterer.pl",
+            &trusted(),
+            &mut authentication,
+        );
+        assert_eq!(authentication.dkim_domains, ["caterer.pl"]);
+        assert_eq!(authentication.spf_domains, ["mail.caterer.pl"]);
+    }
+
+    #[test]
+    fn ignores_failures_and_untrusted_servers() {
+        let mut authentication = Authentication::default();
+        parse_authentication_results("mx.example.com; dkim=fail header.d=caterer.pl; spf=softfail smtp.mailfrom=caterer.pl",
+            &trusted(), &mut authentication);
+        parse_authentication_results("evil.example.net; dkim=pass header.d=caterer.pl", &trusted(), &mut authentication);
+        // The id is the first token, not something hidden in a comment
+        parse_authentication_results("(mx.example.com) evil.example.net; dkim=pass header.i=@caterer.pl",
+            &trusted(), &mut authentication);
+        assert!(authentication.dkim_domains.is_empty());
+        assert!(authentication.spf_domains.is_empty());
+    }
+
+    #[test]
+    fn reads_header_i_when_header_d_is_missing() {
+        let mut authentication = Authentication::default();
+        parse_authentication_results("mx.example.com; dkim=pass header.i=@news.caterer.pl", &trusted(), &mut authentication);
+        assert_eq!(authentication.dkim_domains, ["news.caterer.pl"]);
+    }
+
+    #[test]
+    fn reads_received_spf() {
+        let mut authentication = Authentication::default();
+        parse_received_spf(
+            "Pass (mx.example.com: domain of jane@caterer.pl designates 192.0.2.1 as permitted sender) \
+             client-ip=192.0.2.1; envelope-from=\"jane@caterer.pl\"; helo=mail.caterer.pl;",
+            &mut authentication,
+        );
+        parse_received_spf("fail (not permitted) envelope-from=john@example.net;", &mut authentication);
+        assert_eq!(authentication.spf_domains, ["caterer.pl"]);
+    }
+
+    #[test]
+    fn only_topmost_received_spf_is_trusted() {
+        let config = VerificationConfig { trust_received_spf: true, ..VerificationConfig::default() };
+        let headers = b"Received-SPF: fail envelope-from=jane@caterer.pl;\r\n\
+            Received-SPF: pass envelope-from=jane@caterer.pl;\r\n";
+        assert!(authenticate(headers, None, &config).spf_domains.is_empty());
+    }
+
+    #[test]
+    fn verifies_aligned_single_from() {
+        let authentication = Authentication { dkim_domains: vec!["caterer.pl".to_string()], spf_domains: Vec::new() };
+        let from = |host: &str| Address { name: None, mailbox: Some("jane".to_string()), host: Some(host.to_string()) };
+        assert!(authentication.verifies(VerificationMethod::Dkim, &[from("Caterer.pl")]));
+        assert!(authentication.verifies(VerificationMethod::Dkim, &[from("mail.caterer.pl")]));
+        assert!(!authentication.verifies(VerificationMethod::Dkim, &[from("evilcaterer.pl")]));
+        assert!(!authentication.verifies(VerificationMethod::Spf, &[from("caterer.pl")]));
+        assert!(!authentication.verifies(VerificationMethod::Dkim, &[from("caterer.pl"), from("caterer.pl")]));
+    }
+
+    #[test]
+    fn only_topmost_authentication_results_are_trusted() {
+        let config = VerificationConfig { trusted_authserv_ids: trusted(), ..VerificationConfig::default() };
+        let headers = b"Authentication-Results: mx.example.com; dkim=fail header.d=caterer.pl\r\n\
+            Authentication-Results: mx.example.com; dkim=pass header.d=caterer.pl\r\n\
+            From: jane@caterer.pl\r\n";
+        assert!(authenticate(headers, None, &config).dkim_domains.is_empty());
+
+        let headers = b"Authentication-Results: mx.example.com; dkim=pass header.d=caterer.pl\r\n\
+            Authentication-Results: mx.example.com; dkim=pass header.d=evil.example\r\n\
+            From: jane@caterer.pl\r\n";
+        assert_eq!(authenticate(headers, None, &config).dkim_domains, ["caterer.pl"]);
+    }
+
+    #[test]
+    fn never_verifies_mail_with_several_from_fields() {
+        let config = VerificationConfig {
+            trusted_authserv_ids: trusted(),
+            trust_received_spf: true,
+            ..VerificationConfig::default()
+        };
+        let headers = b"Received-SPF: pass envelope-from=jane@caterer.pl;\r\n\
+            Authentication-Results: mx.example.com; dkim=pass header.d=caterer.pl\r\n\
+            From: jane@caterer.pl\r\n\
+            from: ceo@example.com\r\n";
+        let authentication = authenticate(headers, None, &config);
+        assert!(authentication.dkim_domains.is_empty());
+        assert!(authentication.spf_domains.is_empty());
+    }
+}
@@ -0,0 +1,127 @@
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::warn;
+
+/// Shuts the connection down when nothing was heard from the server for too long,
+/// so that a worker blocked on a dead socket gets an error and reconnects.
+pub struct Watchdog {
+    limit: Duration,
+    last_activity: Mutex<Instant>,
+    // Clone of the socket under the TLS layer of current connection
+    socket: Mutex<Option<TcpStream>>,
+}
+
+impl Watchdog {
+    /// Creates the watchdog together with the thread that enforces it.
+    pub fn spawn(limit: Duration) -> Arc<Watchdog> {
+        let watchdog = Arc::new(Watchdog {
+            limit,
+            last_activity: Mutex::new(Instant::now()),
+            socket: Mutex::new(None),
+        });
+
+        let name = format!("{}-watchdog", thread::current().name().unwrap_or_default());
+        let checked = Arc::clone(&watchdog);
+        thread::Builder::new()
+            .name(name)
+            .spawn(move || loop {
+                thread::sleep(checked.limit / 4);
+                checked.check();
+            })
+            .expect("Failed to spawn watchdog");
+
+        watchdog
+    }
+
+    /// Starts watching a new connection.
+    pub fn arm(&self, socket: TcpStream) {
+        *self.socket.lock().unwrap() = Some(socket);
+        self.feed();
+    }
+
+    /// Stops watching, for when the connection was closed on purpose.
+    pub fn disarm(&self) {
+        *self.socket.lock().unwrap() = None;
+    }
+
+    /// Records that the server is still talking to us.
+    pub fn feed(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    fn check(&self) {
+        let silent_for = self.last_activity.lock().unwrap().elapsed();
+        if silent_for < self.limit {
+            return;
+        }
+
+        if let Some(socket) = self.socket.lock().unwrap().take() {
+            warn!("Server silent for {}s, forcing reconnect", silent_for.as_secs());
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    // Client end of a connection, and the server end that has to stay open
+    fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    fn watchdog(limit: Duration) -> Watchdog {
+        Watchdog { limit, last_activity: Mutex::new(Instant::now()), socket: Mutex::new(None) }
+    }
+
+    #[test]
+    fn fires_only_when_not_fed() {
+        let (client, _server) = connection();
+        let watchdog = watchdog(Duration::from_millis(300));
+        watchdog.arm(client.try_clone().unwrap());
+
+        thread::sleep(Duration::from_millis(200));
+        watchdog.feed();
+        thread::sleep(Duration::from_millis(200));
+        watchdog.check();
+        assert!(watchdog.socket.lock().unwrap().is_some());
+
+        thread::sleep(Duration::from_millis(150));
+        watchdog.check();
+        assert!(watchdog.socket.lock().unwrap().is_none());
+        assert_eq!((&client).read(&mut [0; 8]).unwrap(), 0);
+    }
+
+    #[test]
+    fn leaves_disarmed_connections_alone() {
+        let (client, mut server) = connection();
+        let watchdog = watchdog(Duration::from_millis(10));
+        watchdog.arm(client.try_clone().unwrap());
+        watchdog.disarm();
+
+        thread::sleep(Duration::from_millis(20));
+        watchdog.check();
+        std::io::Write::write_all(&mut server, b"* OK").unwrap();
+        assert_eq!((&client).read(&mut [0; 8]).unwrap(), 4);
+    }
+
+    #[test]
+    fn unblocks_reads_of_a_silent_server() {
+        let (client, _server) = connection();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let watchdog = Watchdog::spawn(Duration::from_millis(100));
+        watchdog.arm(client.try_clone().unwrap());
+
+        let started = Instant::now();
+        assert_eq!((&client).read(&mut [0; 8]).unwrap(), 0);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,583 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use log::{info, warn};
+
+use crate::actions::{Action, ActionContext, Outcome};
+use crate::error::ActionError;
+use crate::paths;
+use crate::template::{Context, Template};
+
+// Longest the retry thread sleeps, so that it notices clock jumps
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct WebhookConfig {
+    // Failed deliveries waiting for another attempt, kept across restarts
+    pub queue_file: String,
+    // Delay before the first retry, doubled after every failed one
+    pub initial_retry_secs: u64,
+    // Delays never grow above this
+    pub max_retry_secs: u64,
+    // Deliveries are given up on after this many attempts
+    pub max_attempts: u32,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> WebhookConfig {
+        WebhookConfig {
+            queue_file: "imap_listener_webhooks.json".to_string(),
+            initial_retry_secs: 30,
+            max_retry_secs: 3600,
+            max_attempts: 12,
+        }
+    }
+}
+
+impl WebhookConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.initial_retry_secs == 0 || self.max_retry_secs < self.initial_retry_secs {
+            problems.push("webhooks.initial_retry_secs has to be between 1 and webhooks.max_retry_secs".to_string());
+        }
+        if self.max_attempts == 0 {
+            problems.push("webhooks.max_attempts has to be greater than 0".to_string());
+        }
+        problems
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    // PEM file with CA certificates to trust besides the system ones, e.g. an internal CA
+    pub ca_file: Option<String>,
+    // PEM client certificate and its key, for servers that ask for one
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    // Don't check the server certificate at all; only ever for testing
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+}
+
+/// JSON value whose strings are templates.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "serde_json::Value")]
+pub enum JsonTemplate {
+    Text(Template),
+    List(Vec<JsonTemplate>),
+    Object(Vec<(String, JsonTemplate)>),
+    // Numbers, booleans and null
+    Other(serde_json::Value),
+}
+
+impl TryFrom<serde_json::Value> for JsonTemplate {
+    type Error = String;
+
+    fn try_from(value: serde_json::Value) -> Result<JsonTemplate, String> {
+        Ok(match value {
+            serde_json::Value::String(text) => JsonTemplate::Text(Template::try_from(text)?),
+            serde_json::Value::Array(items) => {
+                JsonTemplate::List(items.into_iter().map(JsonTemplate::try_from).collect::<Result<_, _>>()?)
+            },
+            serde_json::Value::Object(fields) => JsonTemplate::Object(
+                fields.into_iter()
+                    .map(|(name, value)| JsonTemplate::try_from(value).map(|value| (name, value)))
+                    .collect::<Result<_, _>>()?,
+            ),
+            other => JsonTemplate::Other(other),
+        })
+    }
+}
+
+impl JsonTemplate {
+    pub fn render(&self, context: &Context<'_>) -> serde_json::Value {
+        match self {
+            JsonTemplate::Text(template) => serde_json::Value::String(template.render(context, |value| value)),
+            JsonTemplate::List(items) => items.iter().map(|item| item.render(context)).collect(),
+            JsonTemplate::Object(fields) => {
+                fields.iter().map(|(name, value)| (name.clone(), value.render(context))).collect()
+            },
+            JsonTemplate::Other(value) => value.clone(),
+        }
+    }
+}
+
+/// Settings of the webhook action.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    // Placeholder values are percent-encoded, e.g. "https://tickets/new?subject={subject}"
+    pub url: Template,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, Template>,
+    // JSON sent, with placeholders in its strings; a summary of the mail when not given
+    pub body: Option<JsonTemplate>,
+    // For the whole request, connecting included
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub tls: TlsSettings,
+}
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+fn percent_encode(value: String) -> String {
+    value.bytes()
+        .map(|b| if b.is_ascii_alphanumeric() || b"-._~".contains(&b) { (b as char).to_string() } else { format!("%{b:02X}") })
+        .collect()
+}
+
+// Methods that don't carry a body
+fn bodyless(method: &str) -> bool {
+    method == "GET" || method == "HEAD"
+}
+
+impl Webhook {
+    pub fn validate(&self, rule_name: &str, problems: &mut Vec<String>) {
+        let url = self.url.literal_prefix();
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            problems.push(format!("rule \"{rule_name}\" has a webhook with an URL that doesn't start with http(s)://"));
+        }
+        if self.method.is_empty() || !self.method.bytes().all(|c| c.is_ascii_uppercase()) {
+            problems.push(format!("rule \"{rule_name}\" has a webhook with invalid method {}", self.method));
+        }
+        if self.body.is_some() && bodyless(&self.method) {
+            problems.push(format!("rule \"{rule_name}\" has a webhook with a body, but {} sends none", self.method));
+        }
+        for name in self.headers.keys() {
+            if name.is_empty() || !name.bytes().all(|c| c.is_ascii_graphic() && c != b':') {
+                problems.push(format!("rule \"{rule_name}\" has a webhook with invalid header name {name}"));
+            }
+        }
+        if self.timeout_secs == 0 {
+            problems.push(format!("rule \"{rule_name}\" has a webhook with timeout_secs of 0"));
+        }
+        if self.tls.client_cert.is_some() != self.tls.client_key.is_some() {
+            problems.push(format!("rule \"{rule_name}\" has a webhook with only one of client_cert and client_key"));
+        }
+    }
+
+    fn delivery(&self, values: &Context<'_>) -> Delivery {
+        let body = if bodyless(&self.method) {
+            None
+        } else {
+            let body = match &self.body {
+                Some(body) => body.render(values),
+                None => serde_json::json!({
+                    "account": values.value("account"),
+                    "mailbox": values.value("mailbox"),
+                    "rule": values.value("rule"),
+                    "uid": values.message.uid,
+                    "from": values.value("from"),
+                    "from_name": values.value("from_name"),
+                    "subject": values.value("subject"),
+                    "date": values.message.date.to_rfc3339(),
+                }),
+            };
+            Some(body.to_string())
+        };
+
+        Delivery {
+            description: format!("rule \"{}\", mail of UID {}", values.rule, values.message.uid),
+            method: self.method.clone(),
+            url: self.url.render(values, percent_encode),
+            headers: self.headers.iter().map(|(name, value)| (name.clone(), value.render(values, |value| value))).collect(),
+            body,
+            timeout_secs: self.timeout_secs,
+            tls: self.tls.clone(),
+            attempts: 0,
+            next_attempt: chrono::offset::Utc::now(),
+        }
+    }
+}
+
+impl Action for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    // Deliveries that fail for reasons that may pass are queued and don't count as failures
+    fn run(&self, context: &mut ActionContext<'_>) -> Result<Outcome, ActionError> {
+        context.webhooks.deliver(self.delivery(&context.template_context())).map_err(ActionError::Failed)?;
+        Ok(Outcome::Kept)
+    }
+}
+
+/// A request ready to be sent, as kept in the queue.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Delivery {
+    // What it's about, for logs
+    description: String,
+    method: String,
+    url: String,
+    headers: BTreeMap<String, String>,
+    body: Option<String>,
+    timeout_secs: u64,
+    tls: TlsSettings,
+    attempts: u32,
+    next_attempt: chrono::DateTime<chrono::Utc>,
+}
+
+enum Failure {
+    // Server unreachable, overloaded or broken; may work later
+    Temporary(String),
+    // Server refused the request, sending it again won't help
+    Permanent(String),
+}
+
+fn tls_connector(tls: &TlsSettings) -> Result<native_tls::TlsConnector, Box<dyn Error>> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(ca_file) = &tls.ca_file {
+        for certificate in openssl::x509::X509::stack_from_pem(&fs::read(paths::expand_home(ca_file))?)? {
+            builder.add_root_certificate(native_tls::Certificate::from_der(&certificate.to_der()?)?);
+        }
+    }
+    if let (Some(cert), Some(key)) = (&tls.client_cert, &tls.client_key) {
+        // Passed on as PKCS#12, the only form every native-tls backend takes
+        let cert = openssl::x509::X509::from_pem(&fs::read(paths::expand_home(cert))?)?;
+        let key = openssl::pkey::PKey::private_key_from_pem(&fs::read(paths::expand_home(key))?)?;
+        let pkcs12 = openssl::pkcs12::Pkcs12::builder().build("", "", &key, &cert)?;
+        builder.identity(native_tls::Identity::from_pkcs12(&pkcs12.to_der()?, "")?);
+    }
+    builder.danger_accept_invalid_certs(tls.accept_invalid_certs);
+    Ok(builder.build()?)
+}
+
+fn send(delivery: &Delivery) -> Result<(), Failure> {
+    let connector = tls_connector(&delivery.tls).map_err(|e| Failure::Permanent(format!("can't set up TLS: {e}")))?;
+    let agent = ureq::AgentBuilder::new()
+        .tls_connector(Arc::new(connector))
+        .timeout(Duration::from_secs(delivery.timeout_secs))
+        .build();
+
+    let mut request = agent.request(&delivery.method, &delivery.url);
+    for (name, value) in &delivery.headers {
+        request = request.set(name, value);
+    }
+    let response = match &delivery.body {
+        Some(body) => {
+            if !delivery.headers.keys().any(|name| name.eq_ignore_ascii_case("Content-Type")) {
+                request = request.set("Content-Type", "application/json");
+            }
+            request.send_string(body)
+        },
+        None => request.call(),
+    };
+
+    match response {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(status, response)) => {
+            let message = format!("server answered {status} {}", response.status_text());
+            if status == 408 || status == 429 || status >= 500 {
+                Err(Failure::Temporary(message))
+            } else {
+                Err(Failure::Permanent(message))
+            }
+        },
+        Err(e) => Err(Failure::Temporary(e.to_string())),
+    }
+}
+
+/// Webhook deliveries that failed and are retried with growing delays, shared by all
+/// workers and kept in a file across restarts.
+pub struct WebhookQueue {
+    path: PathBuf,
+    config: WebhookConfig,
+    deliveries: Mutex<Vec<Delivery>>,
+    // Signalled when a delivery is queued
+    queued: Condvar,
+}
+
+impl WebhookQueue {
+    pub fn open(config: &WebhookConfig) -> Result<WebhookQueue, Box<dyn Error>> {
+        let deliveries = match fs::read_to_string(&config.queue_file) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(WebhookQueue {
+            path: PathBuf::from(&config.queue_file),
+            config: config.clone(),
+            deliveries: Mutex::new(deliveries),
+            queued: Condvar::new(),
+        })
+    }
+
+    /// Sends the request right away, queueing it for later if that fails for a reason
+    /// that may pass. Only errors that retrying won't fix are returned.
+    pub fn deliver(&self, delivery: Delivery) -> Result<(), Box<dyn Error>> {
+        match self.attempt(delivery) {
+            Some(Failure::Permanent(e)) => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Sends the delivery, queueing it again if it failed temporarily and has attempts left
+    fn attempt(&self, mut delivery: Delivery) -> Option<Failure> {
+        delivery.attempts += 1;
+        let failure = match send(&delivery) {
+            Ok(()) => {
+                if delivery.attempts > 1 {
+                    info!("Webhook for {} delivered after {} attempts", delivery.description, delivery.attempts);
+                }
+                return None;
+            },
+            Err(failure) => failure,
+        };
+
+        match &failure {
+            Failure::Temporary(e) if delivery.attempts < self.config.max_attempts => {
+                let delay = self.retry_delay(delivery.attempts);
+                warn!("Webhook for {} failed, retrying in {delay}s: {e}", delivery.description);
+                delivery.next_attempt = chrono::offset::Utc::now() + chrono::Duration::seconds(delay as i64);
+                self.push(delivery);
+            },
+            Failure::Temporary(e) => {
+                warn!("Webhook for {} failed {} times, giving up: {e}", delivery.description, delivery.attempts);
+            },
+            Failure::Permanent(e) => warn!("Webhook for {} failed: {e}", delivery.description),
+        }
+        Some(failure)
+    }
+
+    // Seconds to wait after the given number of failed attempts
+    fn retry_delay(&self, attempts: u32) -> u64 {
+        self.config.initial_retry_secs
+            .saturating_mul(2u64.saturating_pow(attempts.saturating_sub(1)))
+            .min(self.config.max_retry_secs)
+    }
+
+    fn push(&self, delivery: Delivery) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        deliveries.push(delivery);
+        if let Err(e) = self.save(&deliveries) {
+            warn!("Failed to write webhook queue: {e}");
+        }
+        self.queued.notify_one();
+    }
+
+    /// Sends queued deliveries as they become due, forever.
+    pub fn retry_forever(&self) -> ! {
+        loop {
+            let due = {
+                let mut deliveries = self.deliveries.lock().unwrap();
+                let now = chrono::offset::Utc::now();
+                let (due, waiting): (Vec<Delivery>, Vec<Delivery>) =
+                    deliveries.drain(..).partition(|delivery| delivery.next_attempt <= now);
+                *deliveries = waiting;
+                if due.is_empty() {
+                    let next = deliveries.iter().map(|delivery| delivery.next_attempt).min();
+                    let idle = next.and_then(|next| (next - now).to_std().ok()).unwrap_or(MAX_IDLE).min(MAX_IDLE);
+                    drop(self.queued.wait_timeout(deliveries, idle).unwrap());
+                    continue;
+                }
+                due
+            };
+
+            for delivery in due {
+                self.attempt(delivery);
+            }
+            // Delivered and dropped ones are only gone from the file now, a crash before
+            // this sends them again rather than losing them
+            let deliveries = self.deliveries.lock().unwrap();
+            if let Err(e) = self.save(&deliveries) {
+                warn!("Failed to write webhook queue: {e}");
+            }
+        }
+    }
+
+    // Only readable by us, the headers often hold tokens
+    fn save(&self, deliveries: &[Delivery]) -> Result<(), Box<dyn Error>> {
+        paths::write_private(&self.path, serde_json::to_string_pretty(deliveries)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    use crate::actions::RuleAction;
+    use crate::rules::Message;
+    use crate::testing;
+
+    // HTTP server answering each request with the next status
+    fn stub(statuses: &[u16]) -> (String, mpsc::Receiver<String>) {
+        testing::http_server(&statuses.iter().map(|status| (*status, "")).collect::<Vec<_>>())
+    }
+
+    fn webhook(json: serde_json::Value) -> Webhook {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn delivery(webhook: &Webhook) -> Delivery {
+        let mut message = Message::example();
+        message.subject = "Menu & prices".to_string();
+        webhook.delivery(&Context { account: "work", mailbox: "INBOX", rule: "menus", message: &message })
+    }
+
+    // Queue in a file of its own, removed again when the test is done with it
+    struct TestQueue(WebhookQueue);
+
+    impl TestQueue {
+        fn new(name: &str, config: WebhookConfig) -> TestQueue {
+            let path = std::env::temp_dir().join(format!("imap-listener-{name}-{}.json", std::process::id()));
+            let _ = fs::remove_file(&path);
+            TestQueue(WebhookQueue::open(&WebhookConfig { queue_file: path.display().to_string(), ..config }).unwrap())
+        }
+
+        fn queued(&self) -> Vec<Delivery> {
+            self.0.deliveries.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for TestQueue {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0.path);
+        }
+    }
+
+    #[test]
+    fn renders_body_template() {
+        let webhook = webhook(serde_json::json!({
+            "url": "https://example.com/hooks",
+            "body": {"text": "Mail from {from}: {subject}", "uid": "{uid}", "count": 1, "tags": ["{rule}", true, null]},
+        }));
+        let body: serde_json::Value = serde_json::from_str(&delivery(&webhook).body.unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({
+            "text": "Mail from jane@caterer.pl: Menu & prices",
+            "uid": "42",
+            "count": 1,
+            "tags": ["menus", true, null],
+        }));
+    }
+
+    #[test]
+    fn sends_method_headers_and_body() {
+        let (url, requests) = stub(&[204]);
+        let webhook = webhook(serde_json::json!({
+            "url": format!("{url}/hooks/{{subject}}"),
+            "method": "PUT",
+            "headers": {"Authorization": "Bearer secret", "X-Mail-Subject": "{subject}"},
+            "body": {"text": "{subject}"},
+        }));
+        send(&delivery(&webhook)).ok().unwrap();
+
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("PUT /hooks/Menu%20%26%20prices HTTP/1.1\r\n"), "{request}");
+        assert!(request.contains("\r\nAuthorization: Bearer secret\r\n"), "{request}");
+        assert!(request.contains("\r\nX-Mail-Subject: Menu & prices\r\n"), "{request}");
+        assert!(request.contains("\r\nContent-Type: application/json\r\n"), "{request}");
+        assert!(request.ends_with("\r\n\r\n{\"text\":\"Menu & prices\"}"), "{request}");
+    }
+
+    #[test]
+    fn posts_summary_by_default_and_nothing_with_get() {
+        let (url, requests) = stub(&[200, 200]);
+        send(&delivery(&webhook(serde_json::json!({"url": url})))).ok().unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST / HTTP/1.1\r\n"), "{request}");
+        let body: serde_json::Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["uid"], 42);
+        assert_eq!(body["from_name"], "Jane Doe");
+        assert_eq!(body["date"], "2024-03-07T12:00:00+00:00");
+
+        send(&delivery(&webhook(serde_json::json!({"url": url, "method": "GET"})))).ok().unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("GET / HTTP/1.1\r\n") && request.ends_with("\r\n\r\n"), "{request}");
+    }
+
+    #[test]
+    fn server_error_is_queued() {
+        let (url, _requests) = stub(&[503]);
+        let queue = TestQueue::new("webhooks-queued", WebhookConfig::default());
+        let before = chrono::offset::Utc::now();
+        queue.0.deliver(delivery(&webhook(serde_json::json!({"url": url})))).unwrap();
+
+        let queued = queue.queued();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].attempts, 1);
+        let delay = (queued[0].next_attempt - before).num_seconds();
+        assert!((29..=31).contains(&delay), "{delay}");
+
+        // Kept across restarts, readable only by us
+        let reopened = WebhookQueue::open(&queue.0.config).unwrap();
+        assert_eq!(reopened.deliveries.lock().unwrap().len(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&queue.0.path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn retry_delays_double_up_to_max() {
+        let config = WebhookConfig { initial_retry_secs: 30, max_retry_secs: 100, ..WebhookConfig::default() };
+        let queue = TestQueue::new("webhooks-delays", config);
+        let delays: Vec<u64> = (1..=5).map(|attempts| queue.0.retry_delay(attempts)).collect();
+        assert_eq!(delays, [30, 60, 100, 100, 100]);
+        assert_eq!(queue.0.retry_delay(u32::MAX), 100);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let (url, requests) = stub(&[500, 502]);
+        let queue = TestQueue::new("webhooks-give-up", WebhookConfig { max_attempts: 2, ..WebhookConfig::default() });
+        queue.0.deliver(delivery(&webhook(serde_json::json!({"url": url})))).unwrap();
+        let retried = queue.0.deliveries.lock().unwrap().pop().unwrap();
+
+        assert!(matches!(queue.0.attempt(retried), Some(Failure::Temporary(_))));
+        assert!(queue.queued().is_empty());
+        assert_eq!(requests.iter().take(2).count(), 2);
+    }
+
+    #[test]
+    fn client_error_is_permanent() {
+        let (url, _requests) = stub(&[404]);
+        let queue = TestQueue::new("webhooks-permanent", WebhookConfig::default());
+        let error = queue.0.deliver(delivery(&webhook(serde_json::json!({"url": url})))).unwrap_err();
+        assert!(error.to_string().contains("404"), "{error}");
+        assert!(queue.queued().is_empty());
+    }
+
+    #[test]
+    fn unreachable_server_is_temporary() {
+        // Bound and dropped right away, so nothing listens there
+        let url = format!("http://{}", TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        assert!(matches!(send(&delivery(&webhook(serde_json::json!({"url": url})))), Err(Failure::Temporary(_))));
+    }
+
+    #[test]
+    fn rejects_invalid_settings_and_retry_policy() {
+        let mut problems = Vec::new();
+        webhook(serde_json::json!({"url": "ftp://x", "method": "get", "body": {}, "headers": {"a b": "c"}, "timeout_secs": 0}))
+            .validate("r", &mut problems);
+        assert_eq!(problems.len(), 4);
+        let mut problems = Vec::new();
+        webhook(serde_json::json!({"url": "http://x", "method": "GET", "body": {}})).validate("r", &mut problems);
+        assert_eq!(problems.len(), 1);
+
+        let action: RuleAction =
+            serde_json::from_value(serde_json::json!({"webhook": {"url": "https://x"}, "on_error": {"retry": 3}})).unwrap();
+        let mut problems = Vec::new();
+        action.validate("r", &mut problems);
+        assert_eq!(problems.len(), 1);
+    }
+}
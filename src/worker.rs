@@ -0,0 +1,728 @@
+use email::rfc2047::decode_rfc2047;
+use email::FromHeader;
+use imap::types::UnsolicitedResponse;
+use imap_proto::types::{Envelope, SectionPath};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::{info, trace, warn};
+
+use crate::actions::{self, ActionContext, Outcome};
+use crate::address::Address;
+use crate::backoff::Backoff;
+use crate::config::{Account, Config};
+use crate::error::{ListenerError, MessageError};
+use crate::folder::{self, Folders, MoveTarget};
+use crate::mime::{self, TextPart};
+use crate::notification::Notifier;
+use crate::oauth2::{Mechanism, OAuth2Authenticator, StaleToken, TokenManager};
+use crate::rules::{self, Message, Rule};
+use crate::smtp::Mailer;
+use crate::ruleset::RuleSet;
+use crate::state::StateStore;
+use crate::status::StatusBoard;
+use crate::verification::{self, Authentication};
+use crate::watchdog::Watchdog;
+use crate::webhook::WebhookQueue;
+
+type ImapStream = native_tls::TlsStream<TcpStream>;
+
+/// Resolved login secret of an account, shared by all of its mailbox workers.
+pub enum Credentials {
+    Password(String),
+    OAuth2(Mechanism, Box<TokenManager>),
+}
+
+fn decode_header(value: &[u8]) -> String {
+    let value = String::from_utf8_lossy(value);
+    if let Some(decoded_value) = decode_rfc2047(&value) {
+        decoded_value
+    } else {
+        value.to_string()
+    }
+}
+
+fn get_subject(envelope: &Envelope<'_>) -> String {
+    envelope.subject.as_deref().map(decode_header).unwrap_or_default()
+}
+
+// Date header, or when the server received the mail if that one is missing or broken
+fn get_date(header: &imap::types::Fetch<'_>, envelope: &Envelope<'_>) -> Option<chrono::DateTime<chrono::Utc>> {
+    envelope.date.as_deref()
+        .and_then(|date| FromHeader::from_header(decode_header(date)).ok())
+        .or_else(|| header.internal_date().map(|date| date.with_timezone(&chrono::Utc)))
+}
+
+// Decoded addresses of an envelope field, without the markers of group syntax
+fn get_addresses(addresses: Option<&Vec<imap_proto::types::Address<'_>>>) -> Vec<Address> {
+    addresses.into_iter().flatten()
+        // Groups start and end with an entry that has no host
+        .filter(|address| address.host.is_some())
+        .map(|address| Address {
+            name: address.name.as_deref().map(decode_header),
+            mailbox: address.mailbox.as_deref().map(|mailbox| String::from_utf8_lossy(mailbox).to_string()),
+            host: address.host.as_deref().map(|host| String::from_utf8_lossy(host).to_string()),
+        })
+        .collect()
+}
+
+fn parse_message(uid: u32, header: &imap::types::Fetch<'_>) -> Result<Message, MessageError> {
+    let envelope = header.envelope().ok_or(MessageError::MissingEnvelope)?;
+    let date = get_date(header, envelope).ok_or(MessageError::InvalidDate)?;
+
+    Ok(Message {
+        uid,
+        from: get_addresses(envelope.from.as_ref()),
+        sender: get_addresses(envelope.sender.as_ref()),
+        reply_to: get_addresses(envelope.reply_to.as_ref()),
+        subject: get_subject(envelope),
+        message_id: envelope.message_id.as_ref().map(|id| String::from_utf8_lossy(id).to_string()),
+        date,
+        headers: header.header().map(|h| String::from_utf8_lossy(h).to_string()),
+        body: None,
+        attachments: Vec::new(),
+        authentication: Authentication::default(),
+    })
+}
+
+/// Server extensions the worker makes use of.
+pub struct ServerCapabilities {
+    // RFC 6851
+    pub move_command: bool,
+    // RFC 4315, needed for UID EXPUNGE
+    pub uidplus: bool,
+    // RFC 2177
+    pub idle: bool,
+}
+
+impl ServerCapabilities {
+    fn query<T: Read + Write>(imap: &mut imap::Session<T>) -> imap::error::Result<ServerCapabilities> {
+        let capabilities = imap.capabilities()?;
+        let server_capabilities = ServerCapabilities {
+            move_command: capabilities.has_str("MOVE"),
+            uidplus: capabilities.has_str("UIDPLUS"),
+            idle: capabilities.has_str("IDLE"),
+        };
+        trace!(
+            "Server supports MOVE: {}, UIDPLUS: {}, IDLE: {}",
+            server_capabilities.move_command, server_capabilities.uidplus, server_capabilities.idle
+        );
+        Ok(server_capabilities)
+    }
+}
+
+/// Logged in connection with the watched mailbox selected.
+pub struct Session {
+    pub imap: imap::Session<ImapStream>,
+    pub capabilities: ServerCapabilities,
+    pub folders: Folders,
+    // Of the selected mailbox, 0 when the server didn't report one
+    pub uid_validity: u32,
+}
+
+/// Logs in and selects the mailbox.
+pub fn open_session(
+    account: &Account,
+    credentials: &Mutex<Credentials>,
+    mailbox: &str,
+    watchdog: &Watchdog,
+) -> Result<Session, ListenerError> {
+    let mut imap = log_in(|| connect(account, watchdog), account, credentials)?;
+
+    // Turn on debug output so we can see the actual traffic coming
+    // from the server and how it is handled in our callback.
+    // This wouldn't be turned on in a production build, but is helpful
+    // in examples and for debugging.
+    imap.debug = false;
+
+    let capabilities = ServerCapabilities::query(&mut imap)?;
+    let folders = Folders::query(&mut imap)?;
+
+    let selected = imap.select(folders.server_name(mailbox)).map_err(ListenerError::Mailbox)?;
+    let uid_validity = selected.uid_validity.unwrap_or_else(|| {
+        warn!("Server didn't report UIDVALIDITY, processed mails may be handled again");
+        0
+    });
+
+    Ok(Session { imap, capabilities, folders, uid_validity })
+}
+
+fn move_email<T: Read + Write>(
+    imap: &mut imap::Session<T>,
+    capabilities: &ServerCapabilities,
+    mail_uid: u32,
+    target_folder: &str,
+) -> imap::error::Result<()> {
+    let mail_uid = mail_uid.to_string();
+    if capabilities.move_command {
+        return imap.uid_mv(&mail_uid, target_folder);
+    }
+
+    folder::uid_copy(imap, &mail_uid, target_folder)?;
+    delete_email(imap, capabilities, &mail_uid)
+}
+
+fn delete_email<T: Read + Write>(
+    imap: &mut imap::Session<T>,
+    capabilities: &ServerCapabilities,
+    mail_uid: &str,
+) -> imap::error::Result<()> {
+    imap.uid_store(mail_uid, "+FLAGS.SILENT (\\Deleted)")?;
+    if capabilities.uidplus {
+        imap.uid_expunge(mail_uid)?;
+    } else {
+        // Also removes any other mail somebody marked as deleted in this mailbox
+        warn!("Server doesn't support UIDPLUS, expunging whole mailbox");
+        imap.expunge()?;
+    }
+    Ok(())
+}
+
+/// Moves a mail out of the watched mailbox, preparing the target folder first.
+pub fn move_to_folder(session: &mut Session, mail_uid: u32, target: &MoveTarget) -> imap::error::Result<()> {
+    let target_folder = session.folders.prepare(&mut session.imap, target)?;
+    move_email(&mut session.imap, &session.capabilities, mail_uid, &target_folder)
+}
+
+/// Whole mail as sent, without marking it seen.
+pub fn fetch_raw(session: &mut Session, mail_uid: u32) -> Result<Vec<u8>, ListenerError> {
+    let messages = session.imap.uid_fetch(mail_uid.to_string(), "BODY.PEEK[]")
+        .map_err(|e| ListenerError::for_message(mail_uid, e))?;
+    messages.iter().find_map(|message| message.body().map(<[u8]>::to_vec))
+        .ok_or(ListenerError::Message { uid: mail_uid, error: MessageError::NotFound })
+}
+
+/// Removes a mail from the watched mailbox for good.
+pub fn delete(session: &mut Session, mail_uid: u32) -> imap::error::Result<()> {
+    delete_email(&mut session.imap, &session.capabilities, &mail_uid.to_string())
+}
+
+fn fetch_query(rules: &[Rule], config: &Config) -> String {
+    // INTERNALDATE stands in for a missing or broken Date header
+    let mut query = vec!["ENVELOPE".to_string(), "INTERNALDATE".to_string()];
+    let header_names = rules::rules_header_names(rules);
+    if rules::rules_need_verification(rules) {
+        // Signatures may cover any header, so all of them are needed
+        query.push("BODY.PEEK[HEADER]".to_string());
+    } else if !header_names.is_empty() {
+        query.push(format!("BODY.PEEK[HEADER.FIELDS ({})]", header_names.join(" ")));
+    }
+    // Text parts and attachments are picked from the structure and fetched separately
+    if rules::rules_need_body(rules) || rules::rules_need_attachments(rules) {
+        query.push("BODYSTRUCTURE".to_string());
+    }
+    // Checking DKIM signatures locally needs the raw body for its hash; one byte more
+    // than the limit tells bodies that are too long apart
+    if rules::rules_need_verification(rules) && !config.verification.dkim_keys.is_empty() {
+        query.push(format!("BODY.PEEK[TEXT]<0.{}>", config.body_max_bytes.saturating_add(1)));
+    }
+    format!("({})", query.join(" "))
+}
+
+// Connects and logs in, once more with a fresh token when a stored one was rejected
+fn log_in<T: Read + Write>(
+    mut connect: impl FnMut() -> imap::error::Result<imap::Client<T>>,
+    account: &Account,
+    credentials: &Mutex<Credentials>,
+) -> Result<imap::Session<T>, ListenerError> {
+    match login(connect()?, account, credentials) {
+        Ok(imap) => Ok(imap),
+        Err(e) if e.is::<StaleToken>() => {
+            // The token is gone by now, so this attempt uses a freshly refreshed one
+            info!("Logging in to {} again, {e}", account.name);
+            login(connect()?, account, credentials).map_err(ListenerError::Login)
+        },
+        Err(e) => Err(ListenerError::Login(e)),
+    }
+}
+
+fn login<T: Read + Write>(
+    client: imap::Client<T>,
+    account: &Account,
+    credentials: &Mutex<Credentials>,
+) -> Result<imap::Session<T>, Box<dyn Error>> {
+    // Held for the whole login, so that only one worker at a time refreshes the token
+    let mut credentials = credentials.lock().unwrap();
+    match &mut *credentials {
+        Credentials::Password(password) => client.login(&account.username, password).map_err(|(e, _)| e.into()),
+        Credentials::OAuth2(mechanism, tokens) => {
+            let stored = tokens.cached().is_some();
+            let authenticator = OAuth2Authenticator {
+                mechanism: *mechanism,
+                user: account.username.clone(),
+                access_token: tokens.access_token()?,
+                host: account.server.clone(),
+                port: account.port,
+            };
+            client.authenticate(mechanism.name(), &authenticator).map_err(|(e, _)| {
+                tokens.invalidate();
+                // Only a rejected fresh token means the login itself is refused
+                match e {
+                    imap::Error::No(_) | imap::Error::Bad(_) if stored => StaleToken(e).into(),
+                    e => e.into(),
+                }
+            })
+        },
+    }
+}
+
+fn connect(account: &Account, watchdog: &Watchdog) -> imap::error::Result<imap::Client<ImapStream>> {
+    imap::ClientBuilder::new(account.server.as_str(), account.port).connect(|domain, tcp| {
+        watchdog.arm(tcp.try_clone()?);
+        let ssl_conn = native_tls::TlsConnector::builder().build()?;
+        Ok(native_tls::TlsConnector::connect(&ssl_conn, domain, tcp)?)
+    })
+}
+
+// Whether the response may mean there is new unseen mail
+fn mailbox_changed(response: &UnsolicitedResponse) -> bool {
+    match response {
+        UnsolicitedResponse::Exists(_) | UnsolicitedResponse::Recent(_) => true,
+        // Flags changed, a mail might have been marked unseen again
+        UnsolicitedResponse::Fetch { .. } => true,
+        _ => false,
+    }
+}
+
+// Empties the queue of responses that arrived while other commands were running
+fn pending_changes<T: Read + Write>(imap: &imap::Session<T>) -> bool {
+    imap.unsolicited_responses.try_iter().filter(mailbox_changed).count() > 0
+}
+
+/// Blocks until the mailbox may have new unseen mail, or until IDLE has to be re-issued.
+fn wait_for_changes(session: &mut Session, config: &Config, watchdog: &Watchdog) -> imap::error::Result<()> {
+    let imap = &mut session.imap;
+    if pending_changes(imap) {
+        return Ok(());
+    }
+
+    if !session.capabilities.idle {
+        loop {
+            thread::sleep(Duration::from_secs(config.refresh_rate));
+            imap.noop()?;
+            watchdog.feed();
+            if pending_changes(imap) {
+                return Ok(());
+            }
+        }
+    }
+
+    // Servers drop IDLE after 29 minutes, so it's ended earlier and the caller re-issues it
+    let outcome = imap.idle()
+        .timeout(Duration::from_secs(config.idle_renew_secs))
+        .keepalive(false)
+        .wait_while(|response| {
+            watchdog.feed();
+            trace!("IDLE response: {response:?}");
+            !mailbox_changed(&response) && !matches!(response, UnsolicitedResponse::Bye { .. })
+        })?;
+    watchdog.feed();
+    trace!("IDLE finished: {outcome:?}");
+
+    Ok(())
+}
+
+// Sequence set of ascending UIDs, with runs written as ranges, e.g. "3:5,9"
+fn uid_set(uids: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &uid in uids {
+        match ranges.last_mut() {
+            Some((_, last)) if last.checked_add(1) == Some(uid) => *last = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+    ranges.iter()
+        .map(|&(first, last)| if first == last { first.to_string() } else { format!("{first}:{last}") })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// State of the thread watching one mailbox.
+struct Worker<'a> {
+    config: &'a Config,
+    account: &'a Account,
+    credentials: &'a Mutex<Credentials>,
+    rules: &'a RuleSet,
+    state: &'a StateStore,
+    status: &'a StatusBoard,
+    webhooks: &'a WebhookQueue,
+    notifier: &'a Notifier,
+    mailer: Option<&'a Mailer>,
+    mailbox: &'a str,
+    // "<account>/<mailbox>"
+    name: String,
+    watchdog: Arc<Watchdog>,
+    backoff: Backoff,
+}
+
+impl Worker<'_> {
+    fn run(&mut self) -> ! {
+        loop {
+            info!("Trying to log in to mailbox");
+
+            let error = match self.connect() {
+                Ok(mut session) => {
+                    self.connected();
+                    self.watch(&mut session)
+                },
+                Err(error) => error,
+            };
+
+            self.back_off(error);
+        }
+    }
+
+    fn connect(&self) -> Result<Session, ListenerError> {
+        let session = open_session(self.account, self.credentials, self.mailbox, &self.watchdog)?;
+        if let Err(e) = self.state.check_uid_validity(&self.account.name, self.mailbox, session.uid_validity) {
+            warn!("Failed to save state: {e}");
+        }
+        Ok(session)
+    }
+
+    fn connected(&mut self) {
+        self.backoff.success();
+        self.status.update(&self.name, |worker| {
+            worker.connected = true;
+            worker.circuit = self.backoff.circuit();
+            worker.consecutive_failures = 0;
+            worker.next_attempt = None;
+        });
+    }
+
+    // Logs and publishes the failure, then sleeps until the next connection attempt
+    fn back_off(&mut self, error: ListenerError) {
+        info!("{error}");
+        let delay = self.backoff.failure(error.failure_kind());
+        info!("Waiting {:.1}s to reconnect", delay.as_secs_f64());
+        self.status.update(&self.name, |worker| {
+            worker.connected = false;
+            worker.circuit = self.backoff.circuit();
+            worker.consecutive_failures = self.backoff.consecutive_failures();
+            worker.last_error = Some(error.to_string());
+            worker.next_attempt = chrono::Duration::from_std(delay).ok().map(|delay| chrono::offset::Utc::now() + delay);
+        });
+
+        thread::sleep(delay);
+
+        self.backoff.attempt();
+        self.status.update(&self.name, |worker| worker.circuit = self.backoff.circuit());
+    }
+
+    /// Handles mails as they come, returns only once the connection has to be re-established.
+    fn watch(&self, session: &mut Session) -> ListenerError {
+        loop {
+            if let Err(error) = self.scan(session) {
+                return error;
+            }
+
+            trace!("Waiting for something to arrive");
+
+            if let Err(error) = wait_for_changes(session, self.config, &self.watchdog) {
+                return error.into();
+            }
+        }
+    }
+
+    // Handles every unseen mail that wasn't handled yet
+    fn scan(&self, session: &mut Session) -> Result<(), ListenerError> {
+        let search_results = session.imap.uid_search("UNSEEN")?;
+        self.watchdog.feed();
+        trace!("Search results: {:?}", &search_results);
+
+        let mut mail_uids: Vec<u32> = search_results.into_iter().collect();
+        mail_uids.sort_unstable();
+        self.forget_gone(session)?;
+
+        for mail_uid in mail_uids {
+            if self.state.is_processed(&self.account.name, self.mailbox, mail_uid) {
+                continue;
+            }
+            trace!("Parsing email of UID {mail_uid}");
+
+            let rules = self.rules.current();
+            match self.process(session, mail_uid, &rules) {
+                Ok(()) => {},
+                Err(ListenerError::Message { error: MessageError::NotFound, .. }) => {
+                    trace!("Mail of UID {mail_uid} is already gone");
+                },
+                Err(error @ ListenerError::Message { .. }) => {
+                    warn!("{error}");
+                    self.quarantine(session, mail_uid);
+                },
+                Err(error) => return Err(error),
+            }
+
+            if let Err(e) = self.state.mark_processed(&self.account.name, self.mailbox, mail_uid) {
+                warn!("Failed to save state: {e}");
+            }
+            self.watchdog.feed();
+        }
+
+        Ok(())
+    }
+
+    // Forgets processed mails that were moved or deleted since
+    fn forget_gone(&self, session: &mut Session) -> Result<(), ListenerError> {
+        let processed = self.state.processed(&self.account.name, self.mailbox);
+        if processed.is_empty() {
+            return Ok(());
+        }
+        let existing = session.imap.uid_search(format!("UID {}", uid_set(&processed)))?;
+        if let Err(e) = self.state.retain(&self.account.name, self.mailbox, &existing) {
+            warn!("Failed to save state: {e}");
+        }
+        Ok(())
+    }
+
+    fn process(&self, session: &mut Session, mail_uid: u32, rules: &[Rule]) -> Result<(), ListenerError> {
+        let message_error = |error| ListenerError::Message { uid: mail_uid, error };
+
+        let messages = session.imap.uid_fetch(mail_uid.to_string(), fetch_query(rules, self.config))
+            .map_err(|e| ListenerError::for_message(mail_uid, e))?;
+        let header = messages.iter().next().ok_or_else(|| message_error(MessageError::NotFound))?;
+        let mut message = parse_message(mail_uid, header).map_err(message_error)?;
+        if let (true, Some(headers)) = (rules::rules_need_verification(rules), header.header()) {
+            let body = header.text().filter(|body| {
+                let fits = body.len() <= self.config.body_max_bytes as usize;
+                if !fits {
+                    trace!("Not checking DKIM of mail of UID {mail_uid} locally, its body is longer than body_max_bytes");
+                }
+                fits
+            });
+            message.authentication = verification::authenticate(headers, body, &self.config.verification);
+            trace!("Mail of UID {mail_uid} authenticated as {:?}", message.authentication);
+        }
+        if rules::rules_need_body(rules) {
+            let parts = header.bodystructure().map(mime::text_parts).unwrap_or_default();
+            message.body = Some(self.fetch_body(session, mail_uid, &parts)?);
+        }
+        if rules::rules_need_attachments(rules) {
+            message.attachments = header.bodystructure().map(mime::attachments).unwrap_or_default();
+        }
+
+        for rule in rules.iter().filter(|rule| rule.condition.matches(&message)) {
+            trace!("Mail from {} matched rule \"{}\"", message.senders_text(), rule.name);
+            let mut context = ActionContext {
+                session: &mut *session,
+                config: self.config,
+                account: self.account,
+                mailbox: self.mailbox,
+                rule,
+                message: &message,
+                watchdog: &self.watchdog,
+                webhooks: self.webhooks,
+                notifier: self.notifier,
+                mailer: self.mailer,
+            };
+            if actions::run_actions(&mut context)? == Outcome::Removed {
+                break; // mail is gone from this mailbox, no other rule can act on it
+            }
+            if rule.stop {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Fetches and decodes the text parts, at most body_max_bytes of them in total
+    fn fetch_body(&self, session: &mut Session, mail_uid: u32, parts: &[TextPart]) -> Result<String, ListenerError> {
+        let mut remaining = self.config.body_max_bytes;
+        let mut sections = Vec::new();
+        for part in parts {
+            let length = part.octets.min(remaining);
+            if length == 0 {
+                continue;
+            }
+            remaining -= length;
+            sections.push((part, length));
+        }
+        if sections.is_empty() {
+            return Ok(String::new());
+        }
+
+        let query: Vec<String> = sections.iter()
+            .map(|(part, length)| format!("BODY.PEEK[{}]<0.{length}>", part.section()))
+            .collect();
+        let messages = session.imap.uid_fetch(mail_uid.to_string(), format!("({})", query.join(" ")))
+            .map_err(|e| ListenerError::for_message(mail_uid, e))?;
+        let fetch = messages.iter().next()
+            .ok_or(ListenerError::Message { uid: mail_uid, error: MessageError::NotFound })?;
+
+        let texts: Vec<String> = sections.iter()
+            .filter_map(|(part, _)| {
+                let data = fetch.section(&SectionPath::Part(part.path.clone(), None))?;
+                Some(mime::decode_part(part, data))
+            })
+            .collect();
+        Ok(texts.join("\n"))
+    }
+
+    // Moves a mail that couldn't be handled out of the watched mailbox, when configured
+    fn quarantine(&self, session: &mut Session, mail_uid: u32) {
+        let Some(folder) = &self.config.quarantine_folder else {
+            return;
+        };
+
+        let target = MoveTarget { folder: folder.clone(), create: true, subscribe: false };
+        match move_to_folder(session, mail_uid, &target) {
+            Ok(()) => info!("Moved mail of UID {mail_uid} to {folder}"),
+            Err(e) => warn!("Failed to move mail of UID {mail_uid} to {folder}: {e}"),
+        }
+    }
+}
+
+/// What all workers share.
+pub struct Shared<'a> {
+    pub config: &'a Config,
+    pub rules: &'a RuleSet,
+    pub state: &'a StateStore,
+    pub status: &'a StatusBoard,
+    pub webhooks: &'a WebhookQueue,
+    pub notifier: &'a Notifier,
+    pub mailer: Option<&'a Mailer>,
+}
+
+/// Watches one mailbox of an account forever, reconnecting whenever the connection breaks.
+pub fn watch_mailbox(shared: &Shared<'_>, account: &Account, credentials: &Mutex<Credentials>, mailbox: &str) {
+    let config = shared.config;
+    let mut worker = Worker {
+        config,
+        account,
+        credentials,
+        rules: shared.rules,
+        state: shared.state,
+        status: shared.status,
+        webhooks: shared.webhooks,
+        notifier: shared.notifier,
+        mailer: shared.mailer,
+        mailbox,
+        name: format!("{}/{}", account.name, mailbox),
+        watchdog: Watchdog::spawn(Duration::from_secs(config.watchdog_secs)),
+        backoff: Backoff::new(config.backoff.clone()),
+    };
+
+    worker.run();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+
+    use crate::config::Auth;
+    use crate::oauth2::OAuth2Config;
+    use crate::password::PasswordSource;
+    use crate::testing::{self, TempFile};
+
+    fn account() -> Account {
+        Account {
+            name: "work".to_string(),
+            server: "imap.example.com".to_string(),
+            port: 993,
+            username: "jane".to_string(),
+            auth: Auth::Password(PasswordSource::Plain("secret".to_string())),
+            mailboxes: vec!["INBOX".to_string()],
+        }
+    }
+
+    // OAuth2 credentials with a stored access token that hasn't expired
+    fn oauth2(token_file: &TempFile, token_url: &str) -> Mutex<Credentials> {
+        let expires_at = chrono::offset::Utc::now().timestamp() + 3600;
+        let token = format!(r#"{{"access_token": "stored", "refresh_token": "refresh", "expires_at": {expires_at}}}"#);
+        std::fs::write(&token_file.0, token).unwrap();
+        let tokens = TokenManager::new(OAuth2Config {
+            token_url: token_url.to_string(),
+            client_id: "listener".to_string(),
+            client_secret: None,
+            scope: None,
+            refresh_token: None,
+            token_file: token_file.path(),
+        }).unwrap();
+        Mutex::new(Credentials::OAuth2(Mechanism::Xoauth2, Box::new(tokens)))
+    }
+
+    fn xoauth2(access_token: &str) -> String {
+        base64::engine::general_purpose::STANDARD.encode(format!("user=jane\x01auth=Bearer {access_token}\x01\x01"))
+    }
+
+    const REJECTED: &str = "+ \r\na1 NO [AUTHENTICATIONFAILED] Invalid credentials\r\n";
+    const ACCEPTED: &str = "+ \r\na1 OK Logged in\r\n";
+
+    #[test]
+    fn logs_in_again_with_a_fresh_token() {
+        let (url, requests) = testing::http_server(&[(200, r#"{"access_token": "fresh", "expires_in": 3600}"#)]);
+        let token_file = TempFile::new("worker-token");
+        let credentials = oauth2(&token_file, &url);
+        let (first, first_sent) = testing::imap_client(REJECTED);
+        let (second, second_sent) = testing::imap_client(ACCEPTED);
+        let mut clients = vec![first, second].into_iter();
+
+        assert!(log_in(|| Ok(clients.next().unwrap()), &account(), &credentials).is_ok());
+        assert!(first_sent.text().contains(&xoauth2("stored")));
+        assert!(second_sent.text().contains(&xoauth2("fresh")));
+        assert!(requests.recv().unwrap().contains("refresh_token=refresh"));
+    }
+
+    #[test]
+    fn gives_up_when_a_fresh_token_is_rejected() {
+        let (url, _requests) = testing::http_server(&[(200, r#"{"access_token": "fresh", "expires_in": 3600}"#)]);
+        let token_file = TempFile::new("worker-token-rejected");
+        let credentials = oauth2(&token_file, &url);
+        let clients = [REJECTED, REJECTED, ACCEPTED].map(|responses| testing::imap_client(responses).0);
+        let mut clients = clients.into_iter();
+
+        let error = log_in(|| Ok(clients.next().unwrap()), &account(), &credentials).unwrap_err();
+        assert!(matches!(&error, ListenerError::Login(e) if !e.is::<StaleToken>()), "{error}");
+        assert_eq!(clients.len(), 1);
+    }
+
+    #[test]
+    fn rejected_passwords_are_not_retried() {
+        let credentials = Mutex::new(Credentials::Password("secret".to_string()));
+        let clients = ["a1 NO [AUTHENTICATIONFAILED] Invalid credentials\r\n", "a1 OK Logged in\r\n"]
+            .map(|responses| testing::imap_client(responses).0);
+        let mut clients = clients.into_iter();
+
+        assert!(matches!(log_in(|| Ok(clients.next().unwrap()), &account(), &credentials), Err(ListenerError::Login(_))));
+        assert_eq!(clients.len(), 1);
+    }
+
+    fn capabilities(move_command: bool) -> ServerCapabilities {
+        ServerCapabilities { move_command, uidplus: true, idle: true }
+    }
+
+    #[test]
+    fn moves_mail_to_folders_with_spaces() {
+        let (mut imap, sent) = testing::imap_session("a2 OK Done\r\n");
+        move_email(&mut imap, &capabilities(true), 42, "Jedzenie &2DzfYw-").unwrap();
+        assert_eq!(sent.text(), "a2 UID MOVE 42 \"Jedzenie &2DzfYw-\"\r\n");
+
+        // Without MOVE the name has to be quoted just the same
+        let (mut imap, sent) = testing::imap_session("a2 OK Copied\r\na3 OK Stored\r\na4 OK Expunged\r\n");
+        move_email(&mut imap, &capabilities(false), 42, "Jedzenie &2DzfYw-").unwrap();
+        assert_eq!(sent.text(), concat!(
+            "a2 UID COPY 42 \"Jedzenie &2DzfYw-\"\r\n",
+            "a3 UID STORE 42 +FLAGS.SILENT (\\Deleted)\r\n",
+            "a4 UID EXPUNGE 42\r\n",
+        ));
+    }
+
+    #[test]
+    fn keeps_mail_when_copying_fails() {
+        let (mut imap, sent) = testing::imap_session("a2 NO [TRYCREATE] No such mailbox\r\n");
+        assert!(move_email(&mut imap, &capabilities(false), 42, "Jedzenie").is_err());
+        assert!(!sent.text().contains("STORE"));
+    }
+
+    #[test]
+    fn writes_uid_sets_with_ranges() {
+        assert_eq!(uid_set(&[7]), "7");
+        assert_eq!(uid_set(&[3, 4, 5, 9, 11, 12]), "3:5,9,11:12");
+        assert_eq!(uid_set(&[u32::MAX - 1, u32::MAX]), format!("{}:{}", u32::MAX - 1, u32::MAX));
+    }
+}